@@ -0,0 +1,221 @@
+use crate::KV6MeshData;
+
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use cgmath::Vector3;
+
+const GLB_MAGIC: [u8; 4] = *b"glTF";
+const GLB_VERSION: u32 = 2;
+const CHUNK_JSON: u32 = 0x4e4f_534a;
+const CHUNK_BIN: u32 = 0x004e_4942;
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+
+/// Which normals go into the `NORMAL` attribute of a glTF export
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GltfNormals {
+    /// The model's own smooth normals, as the viewer lights them
+    Smooth,
+    /// Straight out of each face, for a flat shaded look
+    Face,
+}
+
+// glTF is y-up, the viewer's worldspace is z-up
+fn to_y_up(v: Vector3<f32>) -> [f32; 3] {
+    [v.x, v.z, -v.y + 0.0]
+}
+
+// glTF vertex colors are linear, KV6 colors are sRGB
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Writes the mesh as a self-contained GLB. Vertices are stored around the model's origin,
+/// with the node translated by the pivot `mesh` was generated with, so it shows up where the viewer draws it.
+pub fn save_glb<W: Write>(mesh: &KV6MeshData, pivot: Vector3<f32>, normals: GltfNormals, file: W) -> io::Result<()> {
+    // where the mesher moves everything to center the model on its pivot, see `KV6MeshData::from_data`
+    let pivot_offset = Vector3::new(pivot.x, -pivot.y, -pivot.z);
+
+    let mut positions = Vec::with_capacity(mesh.vertices.len() * 12);
+    let mut vertex_normals = Vec::with_capacity(mesh.vertices.len() * 12);
+    let mut colors = Vec::with_capacity(mesh.vertices.len() * 12);
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+
+    for vertex in &mesh.vertices {
+        let position = to_y_up(Vector3::from(vertex.position) - pivot_offset);
+        for axis in 0..3 {
+            min[axis] = min[axis].min(position[axis]);
+            max[axis] = max[axis].max(position[axis]);
+        }
+
+        // the last entry of the normal table is zero, which glTF doesn't allow
+        let normal = match normals {
+            GltfNormals::Smooth if vertex.normal != [0.0; 3] => vertex.outward_normal(),
+            _ => vertex.face
+        };
+
+        for &value in position.iter() {
+            positions.write_f32::<LittleEndian>(value)?;
+        }
+        for &value in to_y_up(Vector3::from(normal)).iter() {
+            vertex_normals.write_f32::<LittleEndian>(value)?;
+        }
+        for &value in vertex.color.iter() {
+            colors.write_f32::<LittleEndian>(srgb_to_linear(value))?;
+        }
+    }
+
+    let mut indices = Vec::with_capacity(mesh.indices.len() * 4);
+    for &index in &mesh.indices {
+        indices.write_u32::<LittleEndian>(index)?;
+    }
+
+    let translation = to_y_up(pivot_offset);
+    let node = format!("{{\"name\":\"model\",\"translation\":[{},{},{}]", translation[0], translation[1], translation[2]);
+
+    // accessors can't be empty, so a model without faces is just a node
+    let json = if mesh.vertices.is_empty() || mesh.indices.is_empty() {
+        format!(
+            "{{\"asset\":{{\"version\":\"2.0\",\"generator\":\"kv6view-rs\"}},\"scene\":0,\"scenes\":[{{\"nodes\":[0]}}],\"nodes\":[{}}}]}}",
+            node
+        )
+    } else {
+        let views = [&positions, &vertex_normals, &colors, &indices];
+        let mut offset = 0;
+        let mut buffer_views = Vec::new();
+        for (i, view) in views.iter().enumerate() {
+            let target = if i == 3 { ELEMENT_ARRAY_BUFFER } else { ARRAY_BUFFER };
+            buffer_views.push(format!("{{\"buffer\":0,\"byteOffset\":{},\"byteLength\":{},\"target\":{}}}", offset, view.len(), target));
+            offset += view.len();
+        }
+
+        let count = mesh.vertices.len();
+        let accessors = [
+            format!("{{\"bufferView\":0,\"componentType\":{},\"count\":{},\"type\":\"VEC3\",\"min\":[{},{},{}],\"max\":[{},{},{}]}}",
+                FLOAT, count, min[0], min[1], min[2], max[0], max[1], max[2]),
+            format!("{{\"bufferView\":1,\"componentType\":{},\"count\":{},\"type\":\"VEC3\"}}", FLOAT, count),
+            format!("{{\"bufferView\":2,\"componentType\":{},\"count\":{},\"type\":\"VEC3\"}}", FLOAT, count),
+            format!("{{\"bufferView\":3,\"componentType\":{},\"count\":{},\"type\":\"SCALAR\"}}", UNSIGNED_INT, mesh.indices.len()),
+        ];
+
+        format!(
+            concat!(
+                "{{\"asset\":{{\"version\":\"2.0\",\"generator\":\"kv6view-rs\"}},",
+                "\"scene\":0,\"scenes\":[{{\"nodes\":[0]}}],",
+                "\"nodes\":[{},\"mesh\":0}}],",
+                "\"meshes\":[{{\"primitives\":[{{\"attributes\":{{\"POSITION\":0,\"NORMAL\":1,\"COLOR_0\":2}},\"indices\":3,\"material\":0,\"mode\":4}}]}}],",
+                "\"materials\":[{{\"pbrMetallicRoughness\":{{\"metallicFactor\":0,\"roughnessFactor\":1}}}}],",
+                "\"buffers\":[{{\"byteLength\":{}}}],",
+                "\"bufferViews\":[{}],",
+                "\"accessors\":[{}]}}"
+            ),
+            node, offset, buffer_views.join(","), accessors.join(",")
+        )
+    };
+
+    let mut json = json.into_bytes();
+    while json.len() % 4 != 0 {
+        json.push(b' ');
+    }
+
+    let has_buffer = !mesh.vertices.is_empty() && !mesh.indices.is_empty();
+    let binary_length = positions.len() + vertex_normals.len() + colors.len() + indices.len();
+
+    let mut total_length = 12 + 8 + json.len();
+    if has_buffer {
+        total_length += 8 + binary_length;
+    }
+
+    let mut file = BufWriter::new(file);
+    file.write_all(&GLB_MAGIC)?;
+    file.write_u32::<LittleEndian>(GLB_VERSION)?;
+    file.write_u32::<LittleEndian>(total_length as u32)?;
+
+    file.write_u32::<LittleEndian>(json.len() as u32)?;
+    file.write_u32::<LittleEndian>(CHUNK_JSON)?;
+    file.write_all(&json)?;
+
+    // every view is made of 4 byte values, so the chunk is already padded
+    if has_buffer {
+        file.write_u32::<LittleEndian>(binary_length as u32)?;
+        file.write_u32::<LittleEndian>(CHUNK_BIN)?;
+        for view in &[&positions, &vertex_normals, &colors, &indices] {
+            file.write_all(view)?;
+        }
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KV6Data;
+    use byteorder::ByteOrder;
+
+    // the JSON and binary chunks, checking the lengths that lead up to them
+    fn chunks(bytes: &[u8]) -> (String, Option<&[u8]>) {
+        assert_eq!(bytes[0..4], GLB_MAGIC);
+        assert_eq!(LittleEndian::read_u32(&bytes[8..12]) as usize, bytes.len());
+
+        let json_length = LittleEndian::read_u32(&bytes[12..16]) as usize;
+        assert_eq!(LittleEndian::read_u32(&bytes[16..20]), CHUNK_JSON);
+        let json = String::from_utf8(bytes[20..20 + json_length].to_vec()).unwrap();
+
+        let rest = &bytes[20 + json_length..];
+        if rest.is_empty() {
+            return (json, None);
+        }
+        assert_eq!(LittleEndian::read_u32(&rest[4..8]), CHUNK_BIN);
+        assert_eq!(LittleEndian::read_u32(&rest[0..4]) as usize, rest.len() - 8);
+        (json, Some(&rest[8..]))
+    }
+
+    #[test]
+    fn writes_unit_normals_for_every_vertex() {
+        let data = KV6Data::from_bytes(include_bytes!("../../kv6/caco.kv6")).unwrap();
+        let mesh = KV6MeshData::from_data(&data);
+
+        for &normals in &[GltfNormals::Smooth, GltfNormals::Face] {
+            let mut bytes = Vec::new();
+            save_glb(&mesh, data.pivot, normals, &mut bytes).unwrap();
+
+            let (json, binary) = chunks(&bytes);
+            assert!(json.contains(&format!("\"count\":{},", mesh.vertices.len())));
+            assert!(json.contains(&format!("\"count\":{},\"type\":\"SCALAR\"", mesh.indices.len())));
+
+            // positions, normals and colors are three floats each, indices a u32
+            let binary = binary.unwrap();
+            let count = mesh.vertices.len();
+            assert_eq!(binary.len(), count * 36 + mesh.indices.len() * 4);
+
+            for normal in binary[count * 12..count * 24].chunks_exact(12) {
+                let length = (0..3).map(|i| LittleEndian::read_f32(&normal[i * 4..]).powi(2)).sum::<f32>().sqrt();
+                assert!((length - 1.0).abs() < 1e-3, "{:?} normal of length {}", normals, length);
+            }
+        }
+    }
+
+    #[test]
+    fn writes_only_a_node_without_faces() {
+        let mut bytes = Vec::new();
+        save_glb(&KV6MeshData::default(), Vector3::new(0.0, 0.0, 0.0), GltfNormals::Smooth, &mut bytes).unwrap();
+
+        let (json, binary) = chunks(&bytes);
+        assert!(binary.is_none());
+        assert!(!json.contains("accessors"));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{KV6Data, VXLMap, Occupancy, Result};
+use crate::kv6mesh::{KV6Vertex, KV6MeshData, Meshing};
+
+use std::io::{Read, Seek};
+
+glium::implement_vertex!(KV6Vertex, position, normal, face, color, ao);
+
+pub struct KV6Mesh {
+    pub vertex_buffer: glium::VertexBuffer<KV6Vertex>,
+    pub indices: glium::index::IndexBufferAny,
+}
+
+impl KV6Mesh {
+    /// Uploads already generated geometry, with 16-bit indices if there are few enough vertices
+    pub fn from_mesh_data(mesh: &KV6MeshData, display: &glium::Display) -> KV6Mesh {
+        let primitive = glium::index::PrimitiveType::TrianglesList;
+        let indices = if mesh.vertices.len() <= u16::MAX as usize + 1 {
+            let indices = mesh.indices.iter().map(|&i| i as u16).collect::<Vec<_>>();
+            glium::IndexBuffer::new(display, primitive, &indices).unwrap().into()
+        } else {
+            glium::IndexBuffer::new(display, primitive, &mesh.indices).unwrap().into()
+        };
+
+        KV6Mesh {
+            vertex_buffer: glium::VertexBuffer::new(display, &mesh.vertices).unwrap(),
+            indices
+        }
+    }
+
+    pub fn from_data(data: &KV6Data, display: &glium::Display) -> KV6Mesh {
+        KV6Mesh::from_mesh_data(&KV6MeshData::from_data(data), display)
+    }
+
+    pub fn from_file(path: &str, display: &glium::Display) -> Result<KV6Mesh> {
+        let data = crate::load_kv6(path)?;
+        Ok(KV6Mesh::from_data(&data, display))
+    }
+
+    pub fn from_reader<R: Read + Seek>(reader: R, display: &glium::Display) -> Result<KV6Mesh> {
+        let data = KV6Data::from_reader(reader)?;
+        Ok(KV6Mesh::from_data(&data, display))
+    }
+}
+
+/// A VXL map meshed in chunks of `VXL_CHUNK_SIZE` columns, so no single buffer gets too big
+pub struct VXLMesh {
+    pub chunks: Vec<KV6Mesh>,
+}
+
+impl VXLMesh {
+    pub fn from_map(map: &VXLMap, meshing: Meshing, ao: bool, display: &glium::Display) -> VXLMesh {
+        VXLMesh::from_chunks(map, &map.chunks().collect::<Vec<_>>(), meshing, ao, display)
+    }
+
+    /// Meshes chunks already taken from `map` with `VXLMap::chunks`, so that meshing
+    /// them another way doesn't have to work out their visibility and normals again.
+    /// Occlusion is looked up in the whole map, so it carries on across chunk edges.
+    ///
+    /// `Meshing::SurfaceNets` meshes with `Meshing::Faces` instead, chunks only hold the voxels
+    /// next to air so everything underground would look hollow to it.
+    pub fn from_chunks(map: &VXLMap, chunks: &[(u32, u32, KV6Data)], meshing: Meshing, ao: bool, display: &glium::Display) -> VXLMesh {
+        let meshing = match meshing {
+            Meshing::SurfaceNets => Meshing::Faces,
+            meshing => meshing
+        };
+
+        VXLMesh {
+            chunks: chunks.iter()
+                .filter(|(_, _, chunk)| !chunk.voxels.is_empty())
+                .map(|&(x, y, ref chunk)| {
+                    let occupancy = map.chunk_occupancy(x, y);
+                    let ao = if ao { Some(&occupancy as &dyn Occupancy) } else { None };
+                    KV6Mesh::from_mesh_data(&KV6MeshData::from_data_with_occupancy(chunk, meshing, ao), display)
+                })
+                .collect()
+        }
+    }
+}
@@ -0,0 +1,682 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use cgmath::Vector3;
+
+const KV6_MAGIC: [u8; 4] = *b"Kvxl";
+const PALETTE_MAGIC: [u8; 4] = *b"SPal";
+const HEADER_SIZE: u64 = 32;
+const VOXEL_SIZE: u64 = 8;
+const X_ENTRY_SIZE: u64 = 4;
+const XY_ENTRY_SIZE: u64 = 2;
+
+// z is stored as a u16 and column lengths in xy_entries are u16 as well,
+// so nothing past this can be addressed by a well-formed file
+const MAX_DIMENSION: u32 = 1 << 16;
+
+// KV6 visibility bits, set when the neighbour in that direction is air.
+// x grows to the right, y to the front and z downwards
+pub const LEFT_VISIBLE: u8 = 1;    // x - 1
+pub const RIGHT_VISIBLE: u8 = 2;   // x + 1
+pub const BACK_VISIBLE: u8 = 4;    // y - 1
+pub const FRONT_VISIBLE: u8 = 8;   // y + 1
+pub const TOP_VISIBLE: u8 = 16;    // z - 1
+pub const BOTTOM_VISIBLE: u8 = 32; // z + 1
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KV6Color {
+    pub b: u8,
+    pub g: u8,
+    pub r: u8,
+    pub a: u8
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KV6Voxel {
+    pub color: KV6Color,
+    pub z: u16,
+    pub visibility: u8,
+    pub normal_index: u8,
+}
+
+/// 256-colour palette as SLAB6 appends it to KV6 files, components are 6-bit (0..=63)
+#[derive(Copy, Clone)]
+pub struct Palette {
+    pub colors: [[u8; 3]; 256]
+}
+
+impl Palette {
+    pub const BYTE_SIZE: usize = 256 * 3;
+
+    pub fn from_bytes(bytes: &[u8; Palette::BYTE_SIZE]) -> Palette {
+        let mut colors = [[0; 3]; 256];
+        for (color, rgb) in colors.iter_mut().zip(bytes.chunks(3)) {
+            color.copy_from_slice(rgb);
+        }
+        Palette { colors }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Palette::BYTE_SIZE] {
+        let mut bytes = [0; Palette::BYTE_SIZE];
+        for (rgb, color) in bytes.chunks_mut(3).zip(self.colors.iter()) {
+            rgb.copy_from_slice(color);
+        }
+        bytes
+    }
+
+    // scales a 6-bit entry up to 8 bits per component
+    pub fn rgb(&self, index: u8) -> [u8; 3] {
+        let [r, g, b] = self.colors[index as usize];
+        [r << 2 | r >> 4, g << 2 | g >> 4, b << 2 | b >> 4]
+    }
+}
+
+/// Upper bounds enforced while parsing, so untrusted files can't ask for
+/// absurd amounts of memory or time before their contents are checked.
+#[derive(Debug, Copy, Clone)]
+pub struct KV6Limits {
+    pub max_dimension: u32,
+    pub max_voxels: u32,
+    /// Bounds the model's volume, which meshing and editing allocate a cell for each of
+    pub max_cells: u64,
+}
+
+impl Default for KV6Limits {
+    fn default() -> KV6Limits {
+        KV6Limits {
+            max_dimension: 4096,
+            max_voxels: 1 << 24,
+            max_cells: 1 << 24
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct KV6Data {
+    pub size: Vector3<u32>,
+    pub pivot: Vector3<f32>,
+    pub voxel_count: u32,
+    pub voxels: Vec<KV6Voxel>,
+    pub x_entries: Vec<u32>,
+    pub xy_entries: Vec<u16>,
+    pub palette: Option<Palette>,
+    // where each column's voxels start in `voxels`, plus one past the end
+    column_starts: Vec<usize>
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KV6Section {
+    Header,
+    Voxels,
+    XEntries,
+    XYEntries,
+    Palette,
+}
+
+#[derive(Debug)]
+pub enum KV6Error {
+    Io(io::Error),
+    BadMagic { found: [u8; 4] },
+    BadSize { offset: u64, size: Vector3<u32> },
+    TooLarge { offset: u64, size: Vector3<u32>, max_dimension: u32 },
+    TooManyCells { offset: u64, size: Vector3<u32>, max_cells: u64 },
+    TooManyVoxels { offset: u64, voxel_count: u32, max_voxels: u32 },
+    Truncated { section: KV6Section, offset: u64 },
+    ZOutOfRange { offset: u64, z: u16, size_z: u32 },
+    XEntryMismatch { offset: u64, x: u32, stored: u32, counted: u64 },
+    XYSumMismatch { offset: u64, voxel_count: u32, counted: u64 },
+}
+
+impl fmt::Display for KV6Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KV6Section::Header => write!(f, "header"),
+            KV6Section::Voxels => write!(f, "voxel table"),
+            KV6Section::XEntries => write!(f, "x table"),
+            KV6Section::XYEntries => write!(f, "xy table"),
+            KV6Section::Palette => write!(f, "palette"),
+        }
+    }
+}
+
+impl fmt::Display for KV6Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KV6Error::Io(err) =>
+                write!(f, "{}", err),
+            KV6Error::BadMagic { found } =>
+                write!(f, "not a KV6 file: expected magic \"Kvxl\", found {:?}", found),
+            KV6Error::BadSize { offset, size } =>
+                write!(f, "invalid model size {}x{}x{} at byte {}", size.x, size.y, size.z, offset),
+            KV6Error::TooLarge { offset, size, max_dimension } =>
+                write!(f, "model size {}x{}x{} at byte {} exceeds the limit of {}", size.x, size.y, size.z, offset, max_dimension),
+            KV6Error::TooManyCells { offset, size, max_cells } =>
+                write!(f, "model size {}x{}x{} at byte {} holds more than {} cells", size.x, size.y, size.z, offset, max_cells),
+            KV6Error::TooManyVoxels { offset, voxel_count, max_voxels } =>
+                write!(f, "voxel count {} at byte {} exceeds the limit of {}", voxel_count, offset, max_voxels),
+            KV6Error::Truncated { section, offset } =>
+                write!(f, "file ends in the middle of the {} at byte {}", section, offset),
+            KV6Error::ZOutOfRange { offset, z, size_z } =>
+                write!(f, "voxel at byte {} has z {} outside of model height {}", offset, z, size_z),
+            KV6Error::XEntryMismatch { offset, x, stored, counted } =>
+                write!(f, "x entry {} at byte {} says {} voxels but its columns hold {}", x, offset, stored, counted),
+            KV6Error::XYSumMismatch { offset, voxel_count, counted } =>
+                write!(f, "xy table at byte {} holds {} voxels but the header says {}", offset, counted, voxel_count),
+        }
+    }
+}
+
+impl std::error::Error for KV6Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KV6Error::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for KV6Error {
+    fn from(err: io::Error) -> KV6Error {
+        KV6Error::Io(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, KV6Error>;
+
+// turns a short read into a Truncated error pointing at the entry that couldn't be read
+fn in_section<T>(result: io::Result<T>, section: KV6Section, offset: u64) -> Result<T> {
+    result.map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => KV6Error::Truncated { section, offset },
+        _ => KV6Error::Io(err)
+    })
+}
+
+// how many bytes are left after the reader's current position, which it's left at
+fn remaining_length<R: Seek>(file: &mut R) -> io::Result<u64> {
+    let start = file.stream_position()?;
+    let length = file.seek(SeekFrom::End(0))?.saturating_sub(start);
+    file.seek(SeekFrom::Start(start))?;
+    Ok(length)
+}
+
+/// Everything from the reader's current position to the end of the stream,
+/// for the formats that are parsed from memory
+pub(crate) fn read_remaining<R: Read + Seek>(file: &mut R) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(remaining_length(file)? as usize);
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+type Header = ([u8; 4], Vector3<u32>, Vector3<f32>, u32);
+
+fn read_header<R: Read>(file: &mut R) -> io::Result<Header> {
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+
+    let size = Vector3::new(
+        file.read_u32::<LittleEndian>()?,
+        file.read_u32::<LittleEndian>()?,
+        file.read_u32::<LittleEndian>()?
+    );
+
+    let pivot = Vector3::new(
+        file.read_f32::<LittleEndian>()?,
+        file.read_f32::<LittleEndian>()?,
+        file.read_f32::<LittleEndian>()?
+    );
+
+    let voxel_count = file.read_u32::<LittleEndian>()?;
+
+    Ok((magic, size, pivot, voxel_count))
+}
+
+fn read_voxel<R: Read>(file: &mut R) -> io::Result<KV6Voxel> {
+    Ok(KV6Voxel {
+        color: KV6Color {
+            b: file.read_u8()?,
+            g: file.read_u8()?,
+            r: file.read_u8()?,
+            a: file.read_u8()?,
+        },
+        z: file.read_u16::<LittleEndian>()?,
+        visibility: file.read_u8()?,
+        normal_index: file.read_u8()?
+    })
+}
+
+// works out where a stream of `length` bytes runs out, given the sizes from the header
+fn truncation_point(length: u64, voxel_count: u64, size_x: u64, columns: u64) -> Option<KV6Error> {
+    let sections = [
+        (KV6Section::Voxels, VOXEL_SIZE, voxel_count),
+        (KV6Section::XEntries, X_ENTRY_SIZE, size_x),
+        (KV6Section::XYEntries, XY_ENTRY_SIZE, columns),
+    ];
+
+    let mut start = HEADER_SIZE;
+    for &(section, entry_size, entries) in sections.iter() {
+        let end = start + entry_size * entries;
+        if length < end {
+            let offset = start + (length - start) / entry_size * entry_size;
+            return Some(KV6Error::Truncated { section, offset });
+        }
+        start = end;
+    }
+
+    None
+}
+
+impl KV6Data {
+    /// Parses a KV6 model starting at the reader's current position,
+    /// offsets in errors are relative to that position.
+    pub fn from_reader<R: Read + Seek>(file: R) -> Result<KV6Data> {
+        KV6Data::from_reader_with_limits(file, &KV6Limits::default())
+    }
+
+    /// Like `from_reader`, but with caller-chosen limits. The header is checked
+    /// against them and against the length of the stream before anything is allocated.
+    pub fn from_reader_with_limits<R: Read + Seek>(mut file: R, limits: &KV6Limits) -> Result<KV6Data> {
+        let length = remaining_length(&mut file)?;
+
+        let (magic, size, pivot, voxel_count) = in_section(read_header(&mut file), KV6Section::Header, 0)?;
+
+        if magic != KV6_MAGIC {
+            return Err(KV6Error::BadMagic { found: magic });
+        }
+
+        if size.x > MAX_DIMENSION || size.y > MAX_DIMENSION || size.z > MAX_DIMENSION {
+            return Err(KV6Error::BadSize { offset: 4, size });
+        }
+
+        if size.x > limits.max_dimension || size.y > limits.max_dimension || size.z > limits.max_dimension {
+            return Err(KV6Error::TooLarge { offset: 4, size, max_dimension: limits.max_dimension });
+        }
+
+        // each dimension is at most 2^16 by now, so this fits
+        if size.x as u64 * size.y as u64 * size.z as u64 > limits.max_cells {
+            return Err(KV6Error::TooManyCells { offset: 4, size, max_cells: limits.max_cells });
+        }
+
+        if voxel_count > limits.max_voxels {
+            return Err(KV6Error::TooManyVoxels { offset: 28, voxel_count, max_voxels: limits.max_voxels });
+        }
+
+        // size.x * size.y can't overflow a u64, but it can a 32-bit usize
+        let columns = (size.x as usize).checked_mul(size.y as usize)
+            .ok_or(KV6Error::TooLarge { offset: 4, size, max_dimension: limits.max_dimension })?;
+        if let Some(err) = truncation_point(length, voxel_count as u64, size.x as u64, columns as u64) {
+            return Err(err);
+        }
+
+        let mut offset = HEADER_SIZE;
+
+        let mut voxels = Vec::with_capacity(voxel_count as usize);
+        for _ in 0..voxel_count {
+            let voxel = in_section(read_voxel(&mut file), KV6Section::Voxels, offset)?;
+            if voxel.z as u32 >= size.z {
+                return Err(KV6Error::ZOutOfRange { offset, z: voxel.z, size_z: size.z });
+            }
+            voxels.push(voxel);
+            offset += VOXEL_SIZE;
+        }
+
+        let x_offset = offset;
+        let mut x_entries = Vec::with_capacity(size.x as usize);
+        for _ in 0..size.x {
+            x_entries.push(in_section(file.read_u32::<LittleEndian>(), KV6Section::XEntries, offset)?);
+            offset += X_ENTRY_SIZE;
+        }
+
+        let xy_offset = offset;
+        let mut xy_entries = Vec::with_capacity(columns);
+        for _ in 0..columns {
+            xy_entries.push(in_section(file.read_u16::<LittleEndian>(), KV6Section::XYEntries, offset)?);
+            offset += XY_ENTRY_SIZE;
+        }
+
+        let mut total = 0;
+        for x in 0..size.x {
+            let start = x as usize * size.y as usize;
+            let counted: u64 = xy_entries[start..start + size.y as usize].iter().map(|&n| n as u64).sum();
+            if counted != x_entries[x as usize] as u64 {
+                return Err(KV6Error::XEntryMismatch {
+                    offset: x_offset + x as u64 * X_ENTRY_SIZE,
+                    x,
+                    stored: x_entries[x as usize],
+                    counted
+                });
+            }
+            total += counted;
+        }
+
+        if total != voxel_count as u64 {
+            return Err(KV6Error::XYSumMismatch { offset: xy_offset, voxel_count, counted: total });
+        }
+
+        // SLAB6 may append its palette after the xy table, anything else there is left unread
+        let mut magic = Vec::new();
+        file.by_ref().take(PALETTE_MAGIC.len() as u64).read_to_end(&mut magic)?;
+
+        let mut palette = None;
+        if magic == PALETTE_MAGIC {
+            offset += PALETTE_MAGIC.len() as u64;
+
+            let mut palette_bytes = [0; Palette::BYTE_SIZE];
+            in_section(file.read_exact(&mut palette_bytes), KV6Section::Palette, offset)?;
+            palette = Some(Palette::from_bytes(&palette_bytes));
+        }
+
+        Ok(KV6Data {
+            size,
+            pivot,
+            voxel_count,
+            voxels,
+            x_entries,
+            column_starts: column_starts(&xy_entries),
+            xy_entries,
+            palette
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<KV6Data> {
+        KV6Data::from_reader(Cursor::new(bytes))
+    }
+
+    /// Builds a model from its voxels, stored column by column (x major, then y),
+    /// and the number of voxels in each column. `voxel_count` and `x_entries` are derived.
+    pub fn new(size: Vector3<u32>, pivot: Vector3<f32>, voxels: Vec<KV6Voxel>, xy_entries: Vec<u16>, palette: Option<Palette>) -> KV6Data {
+        assert_eq!(xy_entries.len(), size.x as usize * size.y as usize, "one xy entry per column");
+
+        let x_entries = if size.y > 0 {
+            xy_entries.chunks(size.y as usize).map(|columns| columns.iter().map(|&n| n as u32).sum()).collect()
+        } else {
+            vec![0; size.x as usize]
+        };
+
+        let column_starts = column_starts(&xy_entries);
+        assert_eq!(column_starts[column_starts.len() - 1], voxels.len(), "xy entries must add up to the voxel count");
+
+        KV6Data {
+            size,
+            pivot,
+            voxel_count: voxels.len() as u32,
+            voxels,
+            x_entries,
+            xy_entries,
+            palette,
+            column_starts
+        }
+    }
+
+    /// Has to be called after changing `xy_entries` or the number of `voxels` by hand,
+    /// so that the column queries below see the new layout.
+    pub fn rebuild_column_index(&mut self) {
+        self.column_starts = column_starts(&self.xy_entries);
+    }
+
+    /// The voxels of a column, top to bottom. Empty outside of the model, and for columns that
+    /// `voxels` no longer reaches when it was edited without calling `rebuild_column_index`.
+    pub fn column(&self, x: u32, y: u32) -> &[KV6Voxel] {
+        if x >= self.size.x || y >= self.size.y {
+            return &[];
+        }
+
+        let index = x as usize * self.size.y as usize + y as usize;
+        match (self.column_starts.get(index), self.column_starts.get(index + 1)) {
+            (Some(&start), Some(&end)) => self.voxels.get(start..end).unwrap_or(&[]),
+            _ => &[]
+        }
+    }
+
+    pub fn voxel_at(&self, x: u32, y: u32, z: u16) -> Option<&KV6Voxel> {
+        self.column(x, y).iter().find(|voxel| voxel.z == z)
+    }
+
+    /// Every voxel along with its position, in file order
+    pub fn iter_voxels(&self) -> impl Iterator<Item = (u32, u32, u16, &KV6Voxel)> + '_ {
+        (0..self.size.x).flat_map(move |x| {
+            (0..self.size.y).flat_map(move |y| {
+                self.column(x, y).iter().map(move |voxel| (x, y, voxel.z, voxel))
+            })
+        })
+    }
+}
+
+fn column_starts(xy_entries: &[u16]) -> Vec<usize> {
+    let mut starts = Vec::with_capacity(xy_entries.len() + 1);
+    let mut start = 0;
+    starts.push(start);
+    for &entry in xy_entries {
+        start += entry as usize;
+        starts.push(start);
+    }
+    starts
+}
+
+pub fn load_kv6(path: &str) -> Result<KV6Data> {
+    KV6Data::from_reader(BufReader::new(File::open(path)?))
+}
+
+/// Writes `data` out as a "Kvxl" file.
+///
+/// `voxel_count` and `x_entries` are regenerated from `voxels` and `xy_entries`,
+/// so only the column lengths have to be kept up to date when editing.
+pub fn save_kv6<W: Write>(data: &KV6Data, file: W) -> io::Result<()> {
+    let column_count = data.size.x as usize * data.size.y as usize;
+    if data.xy_entries.len() != column_count {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{} xy entries for a {}x{} model", data.xy_entries.len(), data.size.x, data.size.y)));
+    }
+
+    let column_total: usize = data.xy_entries.iter().map(|&n| n as usize).sum();
+    if column_total != data.voxels.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("xy entries hold {} voxels but there are {}", column_total, data.voxels.len())));
+    }
+
+    let mut file = BufWriter::new(file);
+
+    file.write_all(&KV6_MAGIC)?;
+
+    file.write_u32::<LittleEndian>(data.size.x)?;
+    file.write_u32::<LittleEndian>(data.size.y)?;
+    file.write_u32::<LittleEndian>(data.size.z)?;
+
+    file.write_f32::<LittleEndian>(data.pivot.x)?;
+    file.write_f32::<LittleEndian>(data.pivot.y)?;
+    file.write_f32::<LittleEndian>(data.pivot.z)?;
+
+    file.write_u32::<LittleEndian>(data.voxels.len() as u32)?;
+
+    for voxel in &data.voxels {
+        file.write_u8(voxel.color.b)?;
+        file.write_u8(voxel.color.g)?;
+        file.write_u8(voxel.color.r)?;
+        file.write_u8(voxel.color.a)?;
+        file.write_u16::<LittleEndian>(voxel.z)?;
+        file.write_u8(voxel.visibility)?;
+        file.write_u8(voxel.normal_index)?;
+    }
+
+    if data.size.y > 0 {
+        for columns in data.xy_entries.chunks(data.size.y as usize) {
+            file.write_u32::<LittleEndian>(columns.iter().map(|&n| n as u32).sum())?;
+        }
+    } else {
+        for _ in 0..data.size.x {
+            file.write_u32::<LittleEndian>(0)?;
+        }
+    }
+
+    for &entry in &data.xy_entries {
+        file.write_u16::<LittleEndian>(entry)?;
+    }
+
+    if let Some(palette) = &data.palette {
+        file.write_all(&PALETTE_MAGIC)?;
+        file.write_all(&palette.to_bytes())?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODELS: [(&str, &[u8]); 3] = [
+        ("caco", include_bytes!("../../kv6/caco.kv6")),
+        ("desklamp", include_bytes!("../../kv6/desklamp.kv6")),
+        ("light", include_bytes!("../../kv6/light.kv6")),
+    ];
+
+    // a 2x1x4 model with one voxel in each column:
+    // header at 0, voxels at 32 and 40, x entries at 48, xy entries at 56, 60 bytes in all
+    fn fixture() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"Kvxl");
+        for &n in &[2, 1, 4] {
+            bytes.write_u32::<LittleEndian>(n).unwrap();
+        }
+        for _ in 0..3 {
+            bytes.write_f32::<LittleEndian>(0.0).unwrap();
+        }
+        bytes.write_u32::<LittleEndian>(2).unwrap();
+        for &z in &[0, 3] {
+            bytes.extend_from_slice(&[10, 20, 30, 128]);
+            bytes.write_u16::<LittleEndian>(z).unwrap();
+            // only the top face is visible
+            bytes.extend_from_slice(&[16, 0]);
+        }
+        for _ in 0..2 {
+            bytes.write_u32::<LittleEndian>(1).unwrap();
+        }
+        for _ in 0..2 {
+            bytes.write_u16::<LittleEndian>(1).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn loads_fixture() {
+        let data = KV6Data::from_bytes(&fixture()).unwrap();
+        assert_eq!(data.size, Vector3::new(2, 1, 4));
+        assert_eq!(data.voxel_count, 2);
+        let color = &data.voxel_at(1, 0, 3).unwrap().color;
+        assert_eq!((color.b, color.g, color.r, color.a), (10, 20, 30, 128));
+        assert!(data.voxel_at(1, 0, 2).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = fixture();
+        bytes[..4].copy_from_slice(b"Kvx6");
+        assert!(matches!(KV6Data::from_bytes(&bytes), Err(KV6Error::BadMagic { found }) if &found == b"Kvx6"));
+    }
+
+    #[test]
+    fn reports_where_truncated_files_end() {
+        let bytes = fixture();
+        let cases = [
+            (10, KV6Section::Header, 0),
+            (44, KV6Section::Voxels, 40),
+            (50, KV6Section::XEntries, 48),
+            (59, KV6Section::XYEntries, 58),
+        ];
+        for &(length, expected_section, expected_offset) in cases.iter() {
+            match KV6Data::from_bytes(&bytes[..length]) {
+                Err(KV6Error::Truncated { section, offset }) => assert_eq!((section, offset), (expected_section, expected_offset)),
+                other => panic!("cut at {} gave {:?}", length, other.map(|data| data.voxel_count))
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_z_out_of_range() {
+        let mut bytes = fixture();
+        bytes[44] = 4;
+        assert!(matches!(KV6Data::from_bytes(&bytes), Err(KV6Error::ZOutOfRange { offset: 40, z: 4, size_z: 4 })));
+    }
+
+    #[test]
+    fn rejects_x_entries_that_disagree_with_columns() {
+        let mut bytes = fixture();
+        bytes[48] = 2;
+        assert!(matches!(KV6Data::from_bytes(&bytes),
+            Err(KV6Error::XEntryMismatch { offset: 48, x: 0, stored: 2, counted: 1 })));
+    }
+
+    #[test]
+    fn rejects_xy_entries_that_disagree_with_voxel_count() {
+        let mut bytes = fixture();
+        bytes[52] = 0;
+        bytes[58] = 0;
+        assert!(matches!(KV6Data::from_bytes(&bytes),
+            Err(KV6Error::XYSumMismatch { offset: 56, voxel_count: 2, counted: 1 })));
+    }
+
+    #[test]
+    fn rejects_sizes_no_file_can_address() {
+        let mut bytes = fixture();
+        bytes[4..8].copy_from_slice(&(MAX_DIMENSION + 1).to_le_bytes());
+        assert!(matches!(KV6Data::from_bytes(&bytes), Err(KV6Error::BadSize { offset: 4, .. })));
+    }
+
+    #[test]
+    fn rejects_headers_over_the_limits() {
+        let load = |limits: KV6Limits| KV6Data::from_reader_with_limits(Cursor::new(fixture()), &limits);
+
+        let limits = KV6Limits { max_dimension: 3, ..KV6Limits::default() };
+        assert!(matches!(load(limits), Err(KV6Error::TooLarge { offset: 4, max_dimension: 3, .. })));
+
+        let limits = KV6Limits { max_voxels: 1, ..KV6Limits::default() };
+        assert!(matches!(load(limits), Err(KV6Error::TooManyVoxels { offset: 28, voxel_count: 2, max_voxels: 1 })));
+
+        let limits = KV6Limits { max_cells: 7, ..KV6Limits::default() };
+        assert!(matches!(load(limits), Err(KV6Error::TooManyCells { offset: 4, max_cells: 7, .. })));
+
+        let limits = KV6Limits { max_cells: 8, ..KV6Limits::default() };
+        assert!(load(limits).is_ok());
+    }
+
+    #[test]
+    fn rejects_large_volumes_by_default() {
+        // every dimension is under the default limit, but meshing this would need 2^36 cells
+        let mut bytes = fixture();
+        for i in 0..3 {
+            bytes[4 + i * 4..8 + i * 4].copy_from_slice(&4096u32.to_le_bytes());
+        }
+        assert!(matches!(KV6Data::from_bytes(&bytes), Err(KV6Error::TooManyCells { .. })));
+    }
+
+    #[test]
+    fn column_queries_survive_edits_without_reindexing() {
+        let mut data = KV6Data::from_bytes(&fixture()).unwrap();
+        data.voxels.pop();
+        data.xy_entries.clear();
+        assert_eq!(data.iter_voxels().count(), 1);
+        assert!(data.voxel_at(1, 0, 3).is_none());
+
+        data.xy_entries = vec![1, 0];
+        data.rebuild_column_index();
+        assert_eq!(data.column(0, 0).len(), 1);
+    }
+
+    #[test]
+    fn save_round_trips_byte_identical() {
+        for &(name, bytes) in MODELS.iter() {
+            let data = KV6Data::from_bytes(bytes).unwrap();
+            let mut saved = Vec::new();
+            save_kv6(&data, &mut saved).unwrap();
+            assert!(saved == bytes, "{} changed after a load and save", name);
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use crate::{KV6Data, KV6Voxel};
+use crate::voxelgrid::{Occupancy, VoxelGrid, NEIGHBOURS};
+use crate::{LEFT_VISIBLE, RIGHT_VISIBLE, BACK_VISIBLE, FRONT_VISIBLE, TOP_VISIBLE, BOTTOM_VISIBLE};
+
+use std::collections::HashMap;
+
+use cgmath::prelude::*;
+use cgmath::Vector3;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct KV6Vertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub face: [f32; 3],
+    pub color: [u8; 3],
+    /// How much light reaches this corner past the voxels around it, from 0 to 1 when nothing's in the way
+    pub ao: f32
+}
+
+impl KV6Vertex {
+    /// `normal` turned around to point out of the model like `face` does, as exported normals are
+    /// expected to. The normal table points inwards and the viewer's lighting is written around that.
+    pub fn outward_normal(&self) -> [f32; 3] {
+        // + 0.0 turns the -0.0 from negating zero back into 0.0
+        [-self.normal[0] + 0.0, -self.normal[1] + 0.0, -self.normal[2] + 0.0]
+    }
+}
+
+pub mod legacy {
+    use std::f32::consts::PI;
+    use cgmath::Vector3;
+    use cgmath::Zero;
+
+    // kept digit for digit as SLAB6 has it
+    #[allow(clippy::excessive_precision)]
+    const GOLDRAT: f32 = 0.3819660112501052;
+    const LUT_POINTS: usize = 255;
+    const ZMULK: f32 = 2.0 / LUT_POINTS as f32;
+    const ZADDK: f32 = ZMULK * 0.5 - 1.0;
+
+    pub fn create_normal_table() -> Vec<Vector3<f32>> {
+        let mut table = Vec::new();
+
+        for i in 0..LUT_POINTS + 1 {
+            let mut vec = Vector3::new(0.0, 0.0, i as f32 * ZMULK + ZADDK);
+            let g = i as f32 * (GOLDRAT * PI * 2.0);
+            let r = (1.0 - vec.z * vec.z).sqrt();
+            vec.x = -g.cos()*r; // flip X for compat
+            vec.y = g.sin()*r;
+            vec.z *= -1.0;
+            table.push(vec);
+        }
+        
+        table[LUT_POINTS] = Vector3::zero();
+        
+        table
+    }
+}
+
+/// How the visible faces of a model are turned into quads
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Meshing {
+    /// One quad for every face
+    Faces,
+    /// Neighbouring faces of the same color merged into rectangles,
+    /// which also need the same `normal_index` with `match_normals`
+    Greedy { match_normals: bool },
+    /// A smooth surface through the solid voxels with naive surface nets, rounding off their corners
+    SurfaceNets,
+}
+
+/// Triangles for a model, kept on the CPU so they can be uploaded, exported or inspected
+#[derive(Debug, Clone, Default)]
+pub struct KV6MeshData {
+    pub vertices: Vec<KV6Vertex>,
+    // every three make a triangle
+    pub indices: Vec<u32>,
+}
+
+// vertices are only shared when every attribute matches exactly
+type VertexKey = ([u32; 3], [u32; 3], [u32; 3], [u8; 3], u32);
+
+fn vertex_key(vertex: &KV6Vertex) -> VertexKey {
+    let bits = |v: [f32; 3]| [v[0].to_bits(), v[1].to_bits(), v[2].to_bits()];
+    (bits(vertex.position), bits(vertex.normal), bits(vertex.face), vertex.color, vertex.ao.to_bits())
+}
+
+impl KV6MeshData {
+    /// Two triangles for every visible face, in worldspace relative to the model's pivot
+    pub fn from_data(data: &KV6Data) -> KV6MeshData {
+        KV6MeshData::from_data_with(data, Meshing::Faces, false)
+    }
+
+    /// With `ao` the corners of blocky meshes are darkened by the voxels around them.
+    /// Greedy meshing then only merges faces that are lit the same all over, so it merges less.
+    pub fn from_data_with(data: &KV6Data, meshing: Meshing, ao: bool) -> KV6MeshData {
+        KV6MeshData::from_data_with_occupancy(data, meshing, if ao { Some(data) } else { None })
+    }
+
+    /// Like `from_data_with`, but occlusion is looked up in `ao`, in the model's KV6 space.
+    /// That lets part of something larger, like a map chunk, be shaded by what's around it.
+    pub fn from_data_with_occupancy(data: &KV6Data, meshing: Meshing, ao: Option<&dyn Occupancy>) -> KV6MeshData {
+        match meshing {
+            Meshing::Faces => kv6_gen_mesh(data, ao),
+            Meshing::Greedy { match_normals } => kv6_gen_greedy_mesh(data, match_normals, ao),
+            Meshing::SurfaceNets => kv6_gen_surface_net(data)
+        }
+    }
+
+    /// Adds a quad as two triangles, reusing any identical vertex that's already in the mesh.
+    /// It's split along the diagonal with more light at its ends, so occlusion doesn't smear across the quad.
+    fn push_quad(&mut self, lookup: &mut HashMap<VertexKey, u32>, corners: [KV6Vertex; 4]) {
+        let mut indices = [0; 4];
+        for (index, corner) in indices.iter_mut().zip(corners.iter()) {
+            let vertices = &mut self.vertices;
+            *index = *lookup.entry(vertex_key(corner)).or_insert_with(|| {
+                vertices.push(*corner);
+                (vertices.len() - 1) as u32
+            });
+        }
+
+        if corners[1].ao + corners[3].ao > corners[0].ao + corners[2].ao {
+            self.indices.extend_from_slice(&[indices[1], indices[2], indices[3], indices[3], indices[0], indices[1]]);
+        } else {
+            self.indices.extend_from_slice(&[indices[0], indices[1], indices[2], indices[2], indices[3], indices[0]]);
+        }
+    }
+
+    /// One vertex at the centre of every voxel and no triangles, for point clouds
+    pub fn voxel_points(data: &KV6Data) -> KV6MeshData {
+        let normal_table = legacy::create_normal_table();
+
+        KV6MeshData {
+            vertices: data.iter_voxels().map(|(x, y, z, voxel)| KV6Vertex {
+                position: voxel_position(data, x, y, z).into(),
+                normal: normal_table[voxel.normal_index as usize].into(),
+                face: [0.0; 3],
+                color: [voxel.color.r, voxel.color.g, voxel.color.b],
+                ao: 1.0
+            }).collect(),
+            indices: Vec::new()
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+}
+
+// visibility bit, the KV6 space axis the face looks along, then its direction and corners in worldspace
+struct FaceTemplate {
+    visibility: u8,
+    axis: usize,
+    face: [f32; 3],
+    corners: [[f32; 3]; 4]
+}
+
+const FACES: [FaceTemplate; 6] = [
+    FaceTemplate {
+        visibility: FRONT_VISIBLE, axis: 1, face: [0.0, 1.0, 0.0],
+        corners: [[-0.5, 0.5, -0.5], [-0.5, 0.5,  0.5], [ 0.5, 0.5,  0.5], [ 0.5, 0.5, -0.5]]
+    },
+    FaceTemplate {
+        visibility: BACK_VISIBLE, axis: 1, face: [0.0, -1.0, 0.0],
+        corners: [[-0.5, -0.5, -0.5], [ 0.5, -0.5, -0.5], [ 0.5, -0.5,  0.5], [-0.5, -0.5,  0.5]]
+    },
+    FaceTemplate {
+        visibility: TOP_VISIBLE, axis: 2, face: [0.0, 0.0, 1.0],
+        corners: [[-0.5, -0.5,  0.5], [ 0.5, -0.5,  0.5], [ 0.5,  0.5,  0.5], [-0.5,  0.5,  0.5]]
+    },
+    FaceTemplate {
+        visibility: BOTTOM_VISIBLE, axis: 2, face: [0.0, 0.0, -1.0],
+        corners: [[-0.5, -0.5, -0.5], [-0.5,  0.5, -0.5], [ 0.5,  0.5, -0.5], [ 0.5, -0.5, -0.5]]
+    },
+    FaceTemplate {
+        visibility: RIGHT_VISIBLE, axis: 0, face: [-1.0, 0.0, 0.0],
+        corners: [[-0.5, -0.5, -0.5], [-0.5, -0.5,  0.5], [-0.5,  0.5,  0.5], [-0.5,  0.5, -0.5]]
+    },
+    FaceTemplate {
+        visibility: LEFT_VISIBLE, axis: 0, face: [1.0, 0.0, 0.0],
+        corners: [[ 0.5, -0.5, -0.5], [ 0.5,  0.5, -0.5], [ 0.5,  0.5,  0.5], [ 0.5, -0.5,  0.5]]
+    },
+];
+
+fn voxel_position(data: &KV6Data, x: u32, y: u32, z: u16) -> Vector3<f32> {
+    kv6_to_world(data, Vector3::new(x as f32, y as f32, z as f32))
+}
+
+fn kv6_to_world(data: &KV6Data, position: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(
+        -(position.x - data.pivot.x), // set center of the model to the pivot
+        position.y - data.pivot.y,    // and flip model axes for compatibility with worldspace
+        -position.z - data.pivot.z
+    )
+}
+
+// how many of the three voxels in front of a face's corner let light through, from 0 to 3.
+// A corner between two solid sides gets none, whatever's diagonally across.
+fn corner_ao(occupancy: &dyn Occupancy, template: &FaceTemplate, position: [i32; 3], corner: [f32; 3]) -> u8 {
+    let &(dx, dy, dz, _) = NEIGHBOURS.iter().find(|neighbour| neighbour.3 == template.visibility).unwrap();
+    let front = [position[0] + dx, position[1] + dy, position[2] + dz];
+
+    // the corner's direction in KV6 space, along the two axes the face lies in
+    let direction = [-corner[0].signum() as i32, corner[1].signum() as i32, -corner[2].signum() as i32];
+    let (u, v) = ((template.axis + 1) % 3, (template.axis + 2) % 3);
+    let solid = |du: i32, dv: i32| {
+        let mut cell = front;
+        cell[u] += du * direction[u];
+        cell[v] += dv * direction[v];
+        occupancy.is_solid(cell[0], cell[1], cell[2])
+    };
+
+    let (side_u, side_v, diagonal) = (solid(1, 0), solid(0, 1), solid(1, 1));
+    if side_u && side_v {
+        0
+    } else {
+        3 - side_u as u8 - side_v as u8 - diagonal as u8
+    }
+}
+
+// a quad covering the faces of `cells`, which are the corners of a rectangle of voxels.
+// Each corner of the quad is taken from whichever of them sits furthest out in its direction.
+fn emit_quad(mesh: &mut KV6MeshData, lookup: &mut HashMap<VertexKey, u32>, normal_table: &[Vector3<f32>],
+             ao: Option<&dyn Occupancy>, data: &KV6Data, template: &FaceTemplate, cells: [(u32, u32, u16, &KV6Voxel); 4]) {
+    let mut corners = [KV6Vertex::default(); 4];
+
+    for (vertex, &corner) in corners.iter_mut().zip(template.corners.iter()) {
+        let direction = Vector3::from(corner);
+        let &(x, y, z, voxel) = cells.iter()
+            .max_by(|a, b| {
+                let a = voxel_position(data, a.0, a.1, a.2).dot(direction);
+                let b = voxel_position(data, b.0, b.1, b.2).dot(direction);
+                a.partial_cmp(&b).unwrap()
+            })
+            .unwrap();
+
+        *vertex = KV6Vertex {
+            position: (voxel_position(data, x, y, z) + direction).into(),
+            normal: normal_table[voxel.normal_index as usize].into(),
+            face: template.face,
+            color: [voxel.color.r, voxel.color.g, voxel.color.b],
+            ao: ao.map_or(1.0, |ao| corner_ao(ao, template, [x as i32, y as i32, z as i32], corner) as f32 / 3.0)
+        };
+    }
+
+    mesh.push_quad(lookup, corners);
+}
+
+fn kv6_gen_mesh(data: &KV6Data, ao: Option<&dyn Occupancy>) -> KV6MeshData {
+    let mut mesh = KV6MeshData::default();
+    let mut lookup = HashMap::new();
+    let normal_table = legacy::create_normal_table();
+
+    for (x, y, z, voxel) in data.iter_voxels() {
+        let cell = (x, y, z, voxel);
+
+        for template in FACES.iter() {
+            if voxel.visibility & template.visibility > 0 {
+                emit_quad(&mut mesh, &mut lookup, &normal_table, ao, data, template, [cell; 4]);
+            }
+        }
+    }
+
+    mesh
+}
+
+fn kv6_gen_greedy_mesh(data: &KV6Data, match_normals: bool, ao: Option<&dyn Occupancy>) -> KV6MeshData {
+    let mut mesh = KV6MeshData::default();
+    let mut lookup = HashMap::new();
+    let normal_table = legacy::create_normal_table();
+
+    let size = [data.size.x as usize, data.size.y as usize, data.size.z as usize];
+    let voxels = data.iter_voxels().collect::<Vec<_>>();
+
+    for template in FACES.iter() {
+        // walk the slices along the face's axis, merging within each of them
+        let (n, u, v) = (template.axis, (template.axis + 1) % 3, (template.axis + 2) % 3);
+        let position = |i: usize| {
+            let (x, y, z, _) = voxels[i];
+            [x as usize, y as usize, z as usize]
+        };
+
+        // the voxels showing this face, by the slice they're in
+        let mut slices = vec![Vec::new(); size[n]];
+        for (i, &(_, _, _, voxel)) in voxels.iter().enumerate() {
+            if voxel.visibility & template.visibility > 0 {
+                slices[position(i)[n]].push(i);
+            }
+        }
+
+        // one past the index into `voxels` of every cell of the slice, zero where there's no face,
+        // and what a face has to share with its neighbours to be merged with them
+        let mut cells = vec![0; size[u] * size[v]];
+        let mut keys = vec![None; size[u] * size[v]];
+        let mut used = vec![false; size[u] * size[v]];
+
+        for slice in slices.iter().filter(|slice| !slice.is_empty()) {
+            for &i in slice {
+                let (x, y, z, voxel) = voxels[i];
+                let p = position(i);
+
+                // with occlusion faces only merge when it's the same all over them, or it would be stretched across the quad
+                let mut corners = [3; 4];
+                if let Some(ao) = ao {
+                    for (level, &corner) in corners.iter_mut().zip(template.corners.iter()) {
+                        *level = corner_ao(ao, template, [x as i32, y as i32, z as i32], corner);
+                    }
+                }
+
+                cells[p[v] * size[u] + p[u]] = i + 1;
+                keys[p[v] * size[u] + p[u]] =
+                    Some(([voxel.color.r, voxel.color.g, voxel.color.b], if match_normals { voxel.normal_index } else { 0 }, corners));
+            }
+
+            let voxel_at = |i: usize, j: usize| cells[j * size[u] + i].checked_sub(1).map(|cell| voxels[cell]);
+            let key_at = |i: usize, j: usize| keys[j * size[u] + i];
+
+            for cell in used.iter_mut() {
+                *cell = false;
+            }
+
+            for j in 0..size[v] {
+                for i in 0..size[u] {
+                    if used[j * size[u] + i] {
+                        continue;
+                    }
+                    let (color, normal, levels) = match key_at(i, j) {
+                        Some(key) => key,
+                        None => continue
+                    };
+                    let mergeable = levels.iter().all(|&level| level == levels[0]);
+                    let key = Some((color, normal, levels));
+
+                    let mut width = 1;
+                    while mergeable && i + width < size[u] && !used[j * size[u] + i + width] && key_at(i + width, j) == key {
+                        width += 1;
+                    }
+
+                    let mut height = 1;
+                    'grow: while mergeable && j + height < size[v] {
+                        for di in 0..width {
+                            if used[(j + height) * size[u] + i + di] || key_at(i + di, j + height) != key {
+                                break 'grow;
+                            }
+                        }
+                        height += 1;
+                    }
+
+                    for dj in 0..height {
+                        for di in 0..width {
+                            used[(j + dj) * size[u] + i + di] = true;
+                        }
+                    }
+
+                    let corner = |i: usize, j: usize| voxel_at(i, j).unwrap();
+                    let (last_i, last_j) = (i + width - 1, j + height - 1);
+                    emit_quad(&mut mesh, &mut lookup, &normal_table, ao, data, template,
+                        [corner(i, j), corner(last_i, j), corner(i, last_j), corner(last_i, last_j)]);
+                }
+            }
+
+            for &i in slice {
+                let p = position(i);
+                cells[p[v] * size[u] + p[u]] = 0;
+                keys[p[v] * size[u] + p[u]] = None;
+            }
+        }
+    }
+
+    mesh
+}
+
+fn kv6_gen_surface_net(data: &KV6Data) -> KV6MeshData {
+    let mut mesh = KV6MeshData::default();
+    let normal_table = legacy::create_normal_table();
+
+    let grid = VoxelGrid::from(data);
+    let size = data.size.cast::<i32>().unwrap();
+
+    // a vertex for every 2x2x2 block of voxel centres with both solid and air in it,
+    // blocks start one voxel outside the model so its outermost voxels get closed off too
+    let blocks = size + Vector3::new(1, 1, 1);
+    let block_index = |x: i32, y: i32, z: i32| (((x + 1) * blocks.y + y + 1) * blocks.z + z + 1) as usize;
+    let mut block_vertices = vec![u32::MAX; (blocks.x * blocks.y * blocks.z) as usize];
+
+    for x in -1..size.x {
+        for y in -1..size.y {
+            for z in -1..size.z {
+                // bit 0 of a corner is its x offset in the block, bit 1 y and bit 2 z
+                let corner = |i: usize| Vector3::new(x + (i & 1) as i32, y + (i >> 1 & 1) as i32, z + (i >> 2 & 1) as i32);
+                let solid: Vec<bool> = (0..8).map(|i| {
+                    let c = corner(i);
+                    grid.is_solid(c.x, c.y, c.z)
+                }).collect();
+
+                if solid.iter().all(|&s| s) || solid.iter().all(|&s| !s) {
+                    continue;
+                }
+
+                // the average of where the block's edges cross the surface, which is halfway for plain occupancy
+                let mut crossings = Vector3::zero();
+                let mut crossing_count = 0.0;
+                for i in 0..8 {
+                    for &bit in &[1, 2, 4] {
+                        if i & bit == 0 && solid[i] != solid[i | bit] {
+                            crossings += (corner(i) + corner(i | bit)).cast::<f32>().unwrap() * 0.5;
+                            crossing_count += 1.0;
+                        }
+                    }
+                }
+
+                // colors and stored normals of the solid corners, and which way the solid side is
+                let centre = Vector3::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5);
+                let mut color = [0u32; 3];
+                let mut color_count = 0;
+                let mut normal = Vector3::zero();
+                let mut inward = Vector3::zero();
+                for i in (0..8).filter(|&i| solid[i]) {
+                    let c = corner(i);
+                    if let Some(voxel_color) = grid.color(c.x, c.y, c.z) {
+                        color[0] += voxel_color.r as u32;
+                        color[1] += voxel_color.g as u32;
+                        color[2] += voxel_color.b as u32;
+                        color_count += 1;
+                    }
+                    if let Some(normal_index) = grid.normal_index(c.x, c.y, c.z) {
+                        normal += normal_table[normal_index as usize];
+                    }
+                    inward += c.cast::<f32>().unwrap() - centre;
+                }
+
+                // turned into worldspace like positions are, without the pivot
+                let inward = Vector3::new(-inward.x, inward.y, -inward.z);
+                let face = if inward.is_zero() { -normal } else { -inward };
+                // the table points inwards like `inward` does, interior voxels filled in by `VoxelGrid` don't have a normal
+                let normal = if normal.is_zero() { inward } else { normal };
+
+                block_vertices[block_index(x, y, z)] = mesh.vertices.len() as u32;
+                mesh.vertices.push(KV6Vertex {
+                    position: kv6_to_world(data, crossings / crossing_count).into(),
+                    normal: normalize_or_zero(normal).into(),
+                    face: normalize_or_zero(face).into(),
+                    color: [
+                        (color[0] / color_count) as u8,
+                        (color[1] / color_count) as u8,
+                        (color[2] / color_count) as u8
+                    ],
+                    ao: 1.0
+                });
+            }
+        }
+    }
+
+    // a quad for every pair of neighbouring voxels where one is solid and the other air,
+    // joining the vertices of the four blocks around the line between them
+    for axis in 0..3 {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut start = [0; 3];
+        start[axis] = -1;
+
+        for x in start[0]..size.x {
+            for y in start[1]..size.y {
+                for z in start[2]..size.z {
+                    let a = [x, y, z];
+                    let mut b = a;
+                    b[axis] += 1;
+
+                    let a_solid = grid.is_solid(a[0], a[1], a[2]);
+                    if a_solid == grid.is_solid(b[0], b[1], b[2]) {
+                        continue;
+                    }
+
+                    let mut quad = [0; 4];
+                    for (index, &(du, dv)) in quad.iter_mut().zip(&[(1, 1), (0, 1), (0, 0), (1, 0)]) {
+                        let mut block = a;
+                        block[u] -= du;
+                        block[v] -= dv;
+                        *index = block_vertices[block_index(block[0], block[1], block[2])];
+                    }
+
+                    // facing from the solid voxel to the air, in worldspace
+                    let mut outward = [0.0; 3];
+                    outward[axis] = if a_solid { 1.0 } else { -1.0 };
+                    let outward = Vector3::new(-outward[0], outward[1], -outward[2]);
+
+                    // counter clockwise seen from outside, like the blocky meshes
+                    let position = |i: usize| Vector3::from(mesh.vertices[quad[i] as usize].position);
+                    if (position(2) - position(0)).cross(position(3) - position(1)).dot(outward) < 0.0 {
+                        quad.reverse();
+                    }
+
+                    mesh.indices.extend_from_slice(&[quad[0], quad[1], quad[2], quad[2], quad[3], quad[0]]);
+                }
+            }
+        }
+    }
+
+    mesh
+}
+
+fn normalize_or_zero(v: Vector3<f32>) -> Vector3<f32> {
+    if v.is_zero() { v } else { v.normalize() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caco_gets_two_triangles_per_visible_face() {
+        let data = KV6Data::from_bytes(include_bytes!("../../kv6/caco.kv6")).unwrap();
+        let faces: u32 = data.voxels.iter().map(|voxel| voxel.visibility.count_ones()).sum();
+        assert_eq!(faces, 22294);
+
+        let mesh = KV6MeshData::from_data(&data);
+        assert_eq!(mesh.triangle_count(), 44588);
+        assert!(mesh.indices.iter().all(|&index| (index as usize) < mesh.vertices.len()));
+    }
+
+    #[test]
+    fn greedy_meshing_without_ao_merges_by_color_and_normal_only() {
+        let data = KV6Data::from_bytes(include_bytes!("../../kv6/caco.kv6")).unwrap();
+
+        let by_color = KV6MeshData::from_data_with(&data, Meshing::Greedy { match_normals: false }, false);
+        let by_normal = KV6MeshData::from_data_with(&data, Meshing::Greedy { match_normals: true }, false);
+        assert_eq!(by_color.triangle_count(), 16570);
+        assert_eq!(by_normal.triangle_count(), 24912);
+        assert!(by_color.vertices.iter().chain(&by_normal.vertices).all(|vertex| vertex.ao == 1.0));
+
+        // occlusion splits faces that are lit differently
+        let occluded = KV6MeshData::from_data_with(&data, Meshing::Greedy { match_normals: false }, true);
+        assert!(occluded.triangle_count() > by_color.triangle_count());
+        assert!(occluded.vertices.iter().any(|vertex| vertex.ao < 1.0));
+    }
+}
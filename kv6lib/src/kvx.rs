@@ -0,0 +1,541 @@
+use crate::kv6data::read_remaining;
+use crate::{KV6Color, KV6Voxel, KV6Data, KV6Limits, Palette, VoxelGrid, DEFAULT_NORMAL_RADIUS};
+use crate::quantize::{median_cut, Quantizer};
+use crate::{TOP_VISIBLE, BOTTOM_VISIBLE};
+
+use std::collections::HashMap;
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::BufWriter;
+use std::io::Write;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use cgmath::Vector3;
+
+// each mip starts with its byte count followed by six i32s
+const MIP_HEADER_SIZE: usize = 4 + 6 * 4;
+// Build can't address more than 256 voxels along z in a slab
+const MAX_HEIGHT: u32 = 256;
+/// Build only ever loads this many levels of detail
+pub const MAX_MIPS: usize = 5;
+// index 255 is transparent in Build palettes, so generated palettes leave it unused
+const PALETTE_COLORS: usize = 255;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KVXVoxel {
+    pub z: u16,
+    pub color: u8,
+    pub visibility: u8,
+}
+
+/// One level of detail of a KVX model, laid out in columns like `KV6Data`
+/// but with palette indices in place of colors
+pub struct KVXMip {
+    pub size: Vector3<u32>,
+    pub pivot: Vector3<f32>,
+    pub voxels: Vec<KVXVoxel>,
+    pub xy_entries: Vec<u16>,
+}
+
+pub struct KVXData {
+    // highest detail first
+    pub mips: Vec<KVXMip>,
+    pub palette: Palette,
+}
+
+#[derive(Debug)]
+pub enum KVXError {
+    Io(io::Error),
+    Truncated { offset: u64 },
+    NoMips,
+    BadSize { offset: u64, size: Vector3<u32> },
+    TooLarge { offset: u64, size: Vector3<u32>, max_dimension: u32 },
+    TooManyCells { offset: u64, size: Vector3<u32>, max_cells: u64 },
+    BadColumn { offset: u64, x: u32, y: u32 },
+    BadSlab { offset: u64, x: u32, y: u32 },
+}
+
+impl fmt::Display for KVXError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KVXError::Io(err) =>
+                write!(f, "{}", err),
+            KVXError::Truncated { offset } =>
+                write!(f, "KVX file ends early at byte {}", offset),
+            KVXError::NoMips =>
+                write!(f, "KVX file has no mip levels"),
+            KVXError::BadSize { offset, size } =>
+                write!(f, "invalid KVX mip size {}x{}x{} at byte {}", size.x, size.y, size.z, offset),
+            KVXError::TooLarge { offset, size, max_dimension } =>
+                write!(f, "KVX mip size {}x{}x{} at byte {} exceeds the limit of {}", size.x, size.y, size.z, offset, max_dimension),
+            KVXError::TooManyCells { offset, size, max_cells } =>
+                write!(f, "KVX mip size {}x{}x{} at byte {} holds more than {} cells", size.x, size.y, size.z, offset, max_cells),
+            KVXError::BadColumn { offset, x, y } =>
+                write!(f, "column {},{} of the KVX mip at byte {} points outside of the mip", x, y, offset),
+            KVXError::BadSlab { offset, x, y } =>
+                write!(f, "slab at byte {} in column {},{} doesn't fit the model", offset, x, y),
+        }
+    }
+}
+
+impl std::error::Error for KVXError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KVXError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for KVXError {
+    fn from(err: io::Error) -> KVXError {
+        KVXError::Io(err)
+    }
+}
+
+pub type KVXResult<T> = std::result::Result<T, KVXError>;
+
+fn read_i32(bytes: &[u8], offset: usize) -> KVXResult<i32> {
+    bytes.get(offset..offset + 4)
+        .map(LittleEndian::read_i32)
+        .ok_or(KVXError::Truncated { offset: offset as u64 })
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> KVXResult<u16> {
+    bytes.get(offset..offset + 2)
+        .map(LittleEndian::read_u16)
+        .ok_or(KVXError::Truncated { offset: offset as u64 })
+}
+
+// parses the mip whose byte count sits at `start`, bytes past `end` belong to the palette
+fn parse_mip(bytes: &[u8], start: usize, end: usize, limits: &KV6Limits) -> KVXResult<(KVXMip, usize)> {
+    let bytes = &bytes[..end];
+
+    let numbytes = read_i32(bytes, start)? as u32 as usize;
+    let mip_end = (start + 4).checked_add(numbytes)
+        .filter(|&mip_end| mip_end <= end)
+        .ok_or(KVXError::Truncated { offset: end as u64 })?;
+    let bytes = &bytes[..mip_end];
+
+    let size = Vector3::new(
+        read_i32(bytes, start + 4)? as u32,
+        read_i32(bytes, start + 8)? as u32,
+        read_i32(bytes, start + 12)? as u32
+    );
+
+    // 8.8 fixed point
+    let pivot = Vector3::new(
+        read_i32(bytes, start + 16)? as f32 / 256.0,
+        read_i32(bytes, start + 20)? as f32 / 256.0,
+        read_i32(bytes, start + 24)? as f32 / 256.0
+    );
+
+    // converting a mip fills in a cell for each of these, however few voxels it stores
+    if size.x > limits.max_dimension || size.y > limits.max_dimension || size.z > limits.max_dimension {
+        return Err(KVXError::TooLarge { offset: start as u64 + 4, size, max_dimension: limits.max_dimension });
+    }
+    if size.x as u64 * size.y as u64 * size.z as u64 > limits.max_cells {
+        return Err(KVXError::TooManyCells { offset: start as u64 + 4, size, max_cells: limits.max_cells });
+    }
+
+    // both offset tables have to fit in the mip, which bounds everything allocated below
+    let tables = start + MIP_HEADER_SIZE;
+    let data_start = (size.y as u64 + 1).checked_mul(size.x as u64 * 2)
+        .and_then(|xy_table_size| xy_table_size.checked_add(tables as u64 + (size.x as u64 + 1) * 4))
+        .filter(|&data_start| data_start <= mip_end as u64 && size.z <= MAX_HEIGHT)
+        .ok_or(KVXError::BadSize { offset: start as u64 + 4, size })? as usize;
+
+    let xy_table = tables + (size.x as usize + 1) * 4;
+
+    let mut voxels = Vec::new();
+    let mut xy_entries = Vec::with_capacity(size.x as usize * size.y as usize);
+
+    for x in 0..size.x {
+        let x_offset = read_i32(bytes, tables + x as usize * 4)? as u32 as usize;
+        let column_offsets = xy_table + x as usize * (size.y as usize + 1) * 2;
+
+        for y in 0..size.y {
+            let column_start = read_u16(bytes, column_offsets + y as usize * 2)? as usize;
+            let column_end = read_u16(bytes, column_offsets + y as usize * 2 + 2)? as usize;
+
+            let slab_start = tables + x_offset + column_start;
+            let slab_end = tables + x_offset + column_end;
+            if column_end < column_start || slab_start < data_start || slab_end > mip_end {
+                return Err(KVXError::BadColumn { offset: start as u64, x, y });
+            }
+
+            let mut count = 0u16;
+            let mut slab = slab_start;
+            while slab < slab_end {
+                if slab + 3 > slab_end {
+                    return Err(KVXError::BadSlab { offset: slab as u64, x, y });
+                }
+
+                let (ztop, zleng, visibility) = (bytes[slab] as u32, bytes[slab + 1] as usize, bytes[slab + 2]);
+                if slab + 3 + zleng > slab_end || ztop + zleng as u32 > size.z {
+                    return Err(KVXError::BadSlab { offset: slab as u64, x, y });
+                }
+                let colors = &bytes[slab + 3..slab + 3 + zleng];
+
+                // the top and bottom bits only apply to the ends of the slab, the sides to all of it
+                let sides = visibility & !(TOP_VISIBLE | BOTTOM_VISIBLE);
+                for (i, &color) in colors.iter().enumerate() {
+                    let mut voxel_visibility = sides;
+                    if i == 0 { voxel_visibility |= visibility & TOP_VISIBLE; }
+                    if i == zleng - 1 { voxel_visibility |= visibility & BOTTOM_VISIBLE; }
+
+                    voxels.push(KVXVoxel {
+                        z: (ztop + i as u32) as u16,
+                        color,
+                        visibility: voxel_visibility
+                    });
+                }
+
+                count = count.checked_add(zleng as u16)
+                    .ok_or(KVXError::BadSlab { offset: slab as u64, x, y })?;
+                slab += 3 + zleng;
+            }
+
+            xy_entries.push(count);
+        }
+    }
+
+    Ok((KVXMip { size, pivot, voxels, xy_entries }, mip_end))
+}
+
+impl KVXData {
+    /// Parses a KVX model starting at the reader's current position and running to
+    /// the end of the stream, since the palette is stored in the last 768 bytes.
+    pub fn from_reader<R: Read + Seek>(file: R) -> KVXResult<KVXData> {
+        KVXData::from_reader_with_limits(file, &KV6Limits::default())
+    }
+
+    /// Like `from_reader`, but with caller-chosen limits on the size of every mip
+    pub fn from_reader_with_limits<R: Read + Seek>(mut file: R, limits: &KV6Limits) -> KVXResult<KVXData> {
+        let bytes = read_remaining(&mut file)?;
+
+        if bytes.len() < Palette::BYTE_SIZE {
+            return Err(KVXError::Truncated { offset: bytes.len() as u64 });
+        }
+
+        let palette_start = bytes.len() - Palette::BYTE_SIZE;
+        let mut palette_bytes = [0; Palette::BYTE_SIZE];
+        palette_bytes.copy_from_slice(&bytes[palette_start..]);
+        let palette = Palette::from_bytes(&palette_bytes);
+
+        let mut mips = Vec::new();
+        let mut offset = 0;
+        while offset + MIP_HEADER_SIZE <= palette_start {
+            let (mip, end) = parse_mip(&bytes, offset, palette_start, limits)?;
+            mips.push(mip);
+            offset = end;
+        }
+
+        if mips.is_empty() {
+            return Err(KVXError::NoMips);
+        }
+
+        Ok(KVXData { mips, palette })
+    }
+
+    /// The highest detail mip as a KV6 model
+    pub fn to_kv6(&self) -> KV6Data {
+        self.mips[0].to_kv6(&self.palette)
+    }
+}
+
+impl KVXData {
+    /// Converts a KV6 model, quantizing its colors to `palette`, the model's own palette or
+    /// one generated from it, and adds up to `mip_levels - 1` lower levels of detail by halving it.
+    pub fn from_kv6(data: &KV6Data, palette: Option<&Palette>, mip_levels: usize) -> KVXData {
+        let palette = match palette.or(data.palette.as_ref()) {
+            Some(palette) => *palette,
+            None => generate_palette(data.voxels.iter().map(|voxel| voxel.color))
+        };
+
+        let mut quantizer = Quantizer::new((0..PALETTE_COLORS).map(|i| palette.rgb(i as u8)).collect());
+        let mut mips = vec![KVXMip::from_kv6(data, &mut quantizer)];
+
+        let mut grid = VoxelGrid::from(data);
+        while mips.len() < mip_levels.min(MAX_MIPS) && grid.size.x > 1 && grid.size.y > 1 && grid.size.z > 1 {
+            grid = grid.downsample();
+            mips.push(KVXMip::from_kv6(&grid.to_kv6(), &mut quantizer));
+        }
+
+        KVXData { mips, palette }
+    }
+}
+
+impl KVXMip {
+    fn from_kv6(data: &KV6Data, quantizer: &mut Quantizer) -> KVXMip {
+        KVXMip {
+            size: data.size,
+            pivot: data.pivot,
+            voxels: data.voxels.iter().map(|voxel| KVXVoxel {
+                z: voxel.z,
+                color: quantizer.index(voxel.color) as u8,
+                visibility: voxel.visibility
+            }).collect(),
+            xy_entries: data.xy_entries.clone()
+        }
+    }
+
+    // splits a column into slabs of consecutive voxels that share their side visibility,
+    // as (first voxel, voxel count, visibility)
+    fn column_slabs(voxels: &[KVXVoxel]) -> Vec<(usize, usize, u8)> {
+        let sides = |voxel: &KVXVoxel| voxel.visibility & !(TOP_VISIBLE | BOTTOM_VISIBLE);
+
+        let mut slabs = Vec::new();
+        let mut first = 0;
+        while first < voxels.len() {
+            let mut last = first;
+            while last + 1 < voxels.len() && last + 1 - first < 255
+                && voxels[last + 1].z == voxels[last].z + 1
+                && sides(&voxels[last + 1]) == sides(&voxels[first])
+                && voxels[last].visibility & BOTTOM_VISIBLE == 0
+                && voxels[last + 1].visibility & TOP_VISIBLE == 0 {
+                last += 1;
+            }
+
+            let visibility = sides(&voxels[first])
+                | voxels[first].visibility & TOP_VISIBLE
+                | voxels[last].visibility & BOTTOM_VISIBLE;
+            slabs.push((first, last + 1 - first, visibility));
+            first = last + 1;
+        }
+        slabs
+    }
+
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        if self.size.z > MAX_HEIGHT {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("KVX models can't be taller than {} voxels, this one is {}", MAX_HEIGHT, self.size.z)));
+        }
+
+        let columns = self.size.y as usize;
+        let mut x_offsets = Vec::with_capacity(self.size.x as usize + 1);
+        let mut xy_offsets = Vec::with_capacity(self.size.x as usize * (columns + 1));
+        let mut slabs = Vec::new();
+
+        let tables_size = (self.size.x as usize + 1) * 4 + self.size.x as usize * (columns + 1) * 2;
+        let mut voxel = 0;
+        for x in 0..self.size.x as usize {
+            let x_start = slabs.len();
+            x_offsets.push((tables_size + x_start) as u32);
+
+            for y in 0..columns {
+                xy_offsets.push((slabs.len() - x_start) as u16);
+
+                let count = self.xy_entries[x * columns + y] as usize;
+                let column = &self.voxels[voxel..voxel + count];
+                for (first, length, visibility) in KVXMip::column_slabs(column) {
+                    slabs.push(column[first].z as u8);
+                    slabs.push(length as u8);
+                    slabs.push(visibility);
+                    slabs.extend(column[first..first + length].iter().map(|voxel| voxel.color));
+                }
+                voxel += count;
+            }
+
+            // offsets within a slice are 16 bit
+            let slice_size = slabs.len() - x_start;
+            if slice_size > u16::MAX as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("slice {} needs {} bytes, more than KVX can address", x, slice_size)));
+            }
+            xy_offsets.push(slice_size as u16);
+        }
+        x_offsets.push((tables_size + slabs.len()) as u32);
+
+        let mut bytes = Vec::with_capacity(MIP_HEADER_SIZE + tables_size + slabs.len());
+        bytes.write_u32::<LittleEndian>((MIP_HEADER_SIZE - 4 + tables_size + slabs.len()) as u32)?;
+        bytes.write_u32::<LittleEndian>(self.size.x)?;
+        bytes.write_u32::<LittleEndian>(self.size.y)?;
+        bytes.write_u32::<LittleEndian>(self.size.z)?;
+        // 8.8 fixed point
+        bytes.write_i32::<LittleEndian>((self.pivot.x * 256.0).round() as i32)?;
+        bytes.write_i32::<LittleEndian>((self.pivot.y * 256.0).round() as i32)?;
+        bytes.write_i32::<LittleEndian>((self.pivot.z * 256.0).round() as i32)?;
+        for offset in x_offsets {
+            bytes.write_u32::<LittleEndian>(offset)?;
+        }
+        for offset in xy_offsets {
+            bytes.write_u16::<LittleEndian>(offset)?;
+        }
+        bytes.extend(slabs);
+
+        Ok(bytes)
+    }
+
+    /// Looks up colors in `palette`, KVX has no normals so they're estimated from the shape
+    pub fn to_kv6(&self, palette: &Palette) -> KV6Data {
+        let voxels = self.voxels.iter().map(|voxel| {
+            let [r, g, b] = palette.rgb(voxel.color);
+            KV6Voxel {
+                color: KV6Color { b, g, r, a: 128 },
+                z: voxel.z,
+                visibility: voxel.visibility,
+                normal_index: 0
+            }
+        }).collect();
+
+        let mut data = KV6Data::new(self.size, self.pivot, voxels, self.xy_entries.clone(), Some(*palette));
+        data.recompute_normals(DEFAULT_NORMAL_RADIUS);
+        data
+    }
+}
+
+pub fn load_kvx(path: &str) -> KVXResult<KVXData> {
+    KVXData::from_reader(BufReader::new(File::open(path)?))
+}
+
+pub fn save_kvx<W: Write>(kvx: &KVXData, file: W) -> io::Result<()> {
+    let mut file = BufWriter::new(file);
+
+    for mip in &kvx.mips {
+        file.write_all(&mip.to_bytes()?)?;
+    }
+    file.write_all(&kvx.palette.to_bytes())?;
+
+    file.flush()
+}
+
+/// Median cut over the given colors, producing up to 255 entries
+pub fn generate_palette<I: Iterator<Item = KV6Color>>(colors: I) -> Palette {
+    // palettes only hold 6 bits per component, so there's no point telling apart colors beyond that
+    let mut counts: HashMap<[u8; 3], u32> = HashMap::new();
+    for color in colors {
+        *counts.entry([color.r >> 2, color.g >> 2, color.b >> 2]).or_insert(0) += 1;
+    }
+
+    let mut palette = Palette { colors: [[0; 3]; 256] };
+    for (entry, color) in palette.colors.iter_mut().zip(median_cut(counts, PALETTE_COLORS)) {
+        *entry = color;
+    }
+    palette
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use cgmath::InnerSpace;
+
+    // one 1x1x2 mip holding a single slab of two voxels with colors 1 and 2,
+    // then a palette where entry n is (n, 2n, 3n) wrapped to 6 bits
+    fn fixture() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_i32::<LittleEndian>(41).unwrap();
+        for &n in &[1, 1, 2, 128, 128, 512] {
+            bytes.write_i32::<LittleEndian>(n).unwrap();
+        }
+        // x offsets from the end of the header, then column offsets from there
+        bytes.write_i32::<LittleEndian>(12).unwrap();
+        bytes.write_i32::<LittleEndian>(17).unwrap();
+        bytes.write_u16::<LittleEndian>(0).unwrap();
+        bytes.write_u16::<LittleEndian>(5).unwrap();
+        bytes.extend_from_slice(&[0, 2, TOP_VISIBLE | BOTTOM_VISIBLE, 1, 2]);
+        for i in 0..256 {
+            bytes.extend_from_slice(&[(i % 64) as u8, (i * 2 % 64) as u8, (i * 3 % 64) as u8]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn loads_fixture() {
+        let kvx = KVXData::from_reader(Cursor::new(fixture())).unwrap();
+        assert_eq!(kvx.mips.len(), 1);
+
+        let mip = &kvx.mips[0];
+        assert_eq!(mip.size, Vector3::new(1, 1, 2));
+        assert_eq!(mip.pivot, Vector3::new(0.5, 0.5, 2.0));
+        assert_eq!(mip.voxels, vec![
+            KVXVoxel { z: 0, color: 1, visibility: TOP_VISIBLE },
+            KVXVoxel { z: 1, color: 2, visibility: BOTTOM_VISIBLE },
+        ]);
+        assert_eq!(mip.xy_entries, vec![2]);
+        assert_eq!(kvx.to_kv6().voxels.len(), 2);
+    }
+
+    #[test]
+    fn rejects_slabs_that_leave_the_column() {
+        let mut bytes = fixture();
+        // a slab of three voxels with only two colors behind it
+        bytes[41] = 3;
+        assert!(matches!(KVXData::from_reader(Cursor::new(bytes)), Err(KVXError::BadSlab { offset: 40, x: 0, y: 0 })));
+    }
+
+    #[test]
+    fn rejects_mips_over_the_limits() {
+        let load = |limits: KV6Limits| KVXData::from_reader_with_limits(Cursor::new(fixture()), &limits);
+
+        let limits = KV6Limits { max_dimension: 1, ..KV6Limits::default() };
+        assert!(matches!(load(limits), Err(KVXError::TooLarge { offset: 4, max_dimension: 1, .. })));
+
+        let limits = KV6Limits { max_cells: 1, ..KV6Limits::default() };
+        assert!(matches!(load(limits), Err(KVXError::TooManyCells { offset: 4, max_cells: 1, .. })));
+
+        assert!(load(KV6Limits { max_cells: 2, ..KV6Limits::default() }).is_ok());
+    }
+
+    #[test]
+    fn rejects_large_volumes_by_default() {
+        // checked before the tables are even looked for, so these don't need them
+        let with_size = |size: [i32; 3]| {
+            let mut bytes = fixture();
+            for (axis, &n) in size.iter().enumerate() {
+                bytes[4 + axis * 4..8 + axis * 4].copy_from_slice(&n.to_le_bytes());
+            }
+            KVXData::from_reader(Cursor::new(bytes))
+        };
+
+        assert!(matches!(with_size([1, 1_000_000, 256]), Err(KVXError::TooLarge { offset: 4, .. })));
+        assert!(matches!(with_size([4096, 4096, 2]), Err(KVXError::TooManyCells { offset: 4, .. })));
+    }
+    const MODELS: [(&str, &[u8]); 3] = [
+        ("caco", include_bytes!("../../kv6/caco.kv6")),
+        ("desklamp", include_bytes!("../../kv6/desklamp.kv6")),
+        ("light", include_bytes!("../../kv6/light.kv6")),
+    ];
+
+    #[test]
+    fn saved_models_load_back_the_same() {
+        for &(name, bytes) in MODELS.iter() {
+            let kvx = KVXData::from_kv6(&KV6Data::from_bytes(bytes).unwrap(), None, MAX_MIPS);
+            let mut saved = Vec::new();
+            save_kvx(&kvx, &mut saved).unwrap();
+            let loaded = KVXData::from_reader(Cursor::new(saved)).unwrap();
+
+            assert!(loaded.palette.to_bytes()[..] == kvx.palette.to_bytes()[..], "{} palette changed", name);
+            assert_eq!(loaded.mips.len(), kvx.mips.len(), "{}", name);
+            for (loaded, mip) in loaded.mips.iter().zip(&kvx.mips) {
+                assert_eq!(loaded.size, mip.size, "{}", name);
+                // pivots are stored in 8.8 fixed point
+                assert!((loaded.pivot - mip.pivot).magnitude() < 0.01, "{} pivot moved", name);
+                assert!(loaded.voxels == mip.voxels, "{} voxels changed", name);
+                assert_eq!(loaded.xy_entries, mip.xy_entries, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_models_own_palette() {
+        let mut data = KV6Data::from_bytes(MODELS[0].1).unwrap();
+        let own = generate_palette([KV6Color { b: 0, g: 0, r: 255, a: 128 }].iter().cloned());
+        data.palette = Some(own);
+
+        let kvx = KVXData::from_kv6(&data, None, 1);
+        assert!(kvx.palette.to_bytes()[..] == own.to_bytes()[..]);
+
+        let given = generate_palette([KV6Color { b: 255, g: 0, r: 0, a: 128 }].iter().cloned());
+        let kvx = KVXData::from_kv6(&data, Some(&given), 1);
+        assert!(kvx.palette.to_bytes()[..] == given.to_bytes()[..]);
+    }
+}
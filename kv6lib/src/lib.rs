@@ -0,0 +1,30 @@
+pub mod kv6data;
+pub mod kv6mesh;
+#[cfg(feature = "glium")]
+pub mod glmesh;
+pub mod voxelgrid;
+pub mod normals;
+pub mod quantize;
+pub mod kvx;
+pub mod vox;
+pub mod vxl;
+pub mod qb;
+pub mod obj;
+pub mod ply;
+pub mod glb;
+pub mod stl;
+
+pub use kv6data::*;
+pub use kv6mesh::*;
+#[cfg(feature = "glium")]
+pub use glmesh::*;
+pub use voxelgrid::*;
+pub use normals::*;
+pub use kvx::*;
+pub use vox::*;
+pub use vxl::*;
+pub use qb::*;
+pub use obj::*;
+pub use ply::*;
+pub use glb::*;
+pub use stl::*;
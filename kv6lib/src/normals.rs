@@ -0,0 +1,123 @@
+use crate::{KV6Data, VoxelGrid, Occupancy, NEIGHBOURS};
+use crate::kv6mesh::legacy;
+
+use cgmath::prelude::*;
+use cgmath::Vector3;
+
+/// Radius SLAB6 samples over when it estimates normals
+pub const DEFAULT_NORMAL_RADIUS: i32 = 2;
+
+// sum of the directions a cell's open faces point in, zero for interior and air cells
+fn open_face_direction<G: Occupancy>(grid: &G, x: i32, y: i32, z: i32) -> Vector3<f32> {
+    let mut direction = Vector3::zero();
+    if !grid.is_solid(x, y, z) {
+        return direction;
+    }
+
+    for &(dx, dy, dz, _) in NEIGHBOURS.iter() {
+        if !grid.is_solid(x + dx, y + dy, z + dz) {
+            direction += Vector3::new(dx as f32, dy as f32, dz as f32);
+        }
+    }
+    direction
+}
+
+/// Estimates the surface normal at a cell in KV6 space, by averaging the open faces
+/// of every surface voxel within `radius`. Zero if nothing around it is exposed.
+pub fn estimate_normal<G: Occupancy>(grid: &G, x: i32, y: i32, z: i32, radius: i32) -> Vector3<f32> {
+    let mut sum = Vector3::zero();
+
+    for dx in -radius..=radius {
+        for dy in -radius..=radius {
+            for dz in -radius..=radius {
+                if dx * dx + dy * dy + dz * dz > radius * radius {
+                    continue;
+                }
+
+                let direction = open_face_direction(grid, x + dx, y + dy, z + dz);
+                if direction.magnitude2() > 0.0 {
+                    sum += direction.normalize();
+                }
+            }
+        }
+    }
+
+    // small symmetric shapes can cancel out completely, fall back to the cell's own faces then
+    if sum.magnitude2() < 1e-6 {
+        sum = open_face_direction(grid, x, y, z);
+    }
+
+    if sum.magnitude2() > 0.0 {
+        sum.normalize()
+    } else {
+        sum
+    }
+}
+
+/// Picks the entry of `legacy::create_normal_table()` closest to a KV6 space direction
+pub fn nearest_normal_index(normal_table: &[Vector3<f32>], direction: Vector3<f32>) -> u8 {
+    if direction.magnitude2() == 0.0 {
+        // the last entry of the table is the zero vector
+        return (normal_table.len() - 1) as u8;
+    }
+
+    // the table is in viewer space, which flips x and z, and its
+    // normals point into the model rather than away from it
+    let direction = Vector3::new(direction.x, -direction.y, direction.z).normalize();
+
+    let mut best = 0;
+    for (i, normal) in normal_table.iter().enumerate() {
+        if normal.dot(direction) > normal_table[best].dot(direction) {
+            best = i;
+        }
+    }
+    best as u8
+}
+
+pub fn estimate_normal_index<G: Occupancy>(grid: &G, normal_table: &[Vector3<f32>], x: i32, y: i32, z: i32, radius: i32) -> u8 {
+    nearest_normal_index(normal_table, estimate_normal(grid, x, y, z, radius))
+}
+
+impl KV6Data {
+    /// Regenerates every voxel's `normal_index` from the model's shape
+    pub fn recompute_normals(&mut self, radius: i32) {
+        let grid = VoxelGrid::from(&*self);
+        let normal_table = legacy::create_normal_table();
+
+        let normals: Vec<u8> = self.iter_voxels()
+            .map(|(x, y, z, _)| estimate_normal_index(&grid, &normal_table, x as i32, y as i32, z as i32, radius))
+            .collect();
+
+        for (voxel, normal_index) in self.voxels.iter_mut().zip(normals) {
+            voxel.normal_index = normal_index;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODELS: [(&str, &[u8]); 3] = [
+        ("caco", include_bytes!("../../kv6/caco.kv6")),
+        ("desklamp", include_bytes!("../../kv6/desklamp.kv6")),
+        ("light", include_bytes!("../../kv6/light.kv6")),
+    ];
+
+    #[test]
+    fn estimated_normals_agree_with_stored_ones() {
+        let normal_table = legacy::create_normal_table();
+
+        for &(name, bytes) in MODELS.iter() {
+            let stored = KV6Data::from_bytes(bytes).unwrap();
+            let mut estimated = stored.clone();
+            estimated.recompute_normals(DEFAULT_NORMAL_RADIUS);
+
+            // the estimate isn't an exact copy of whatever made the stored ones, so only close on average
+            let mean = stored.voxels.iter().zip(&estimated.voxels)
+                .map(|(a, b)| normal_table[a.normal_index as usize].dot(normal_table[b.normal_index as usize]))
+                .sum::<f32>() / stored.voxels.len() as f32;
+            assert!(mean > 0.9, "{} estimated normals are {} off the stored ones on average", name, mean.acos().to_degrees());
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::KV6MeshData;
+
+use std::collections::HashMap;
+
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// The distinct colors of a mesh laid out on a square texture, so OBJ files can carry them as UVs
+pub struct ObjPalette {
+    pub colors: Vec<[u8; 3]>,
+    pub side: u32,
+    lookup: HashMap<[u8; 3], usize>,
+}
+
+impl ObjPalette {
+    pub fn new(mesh: &KV6MeshData) -> ObjPalette {
+        let mut colors = Vec::new();
+        let mut lookup = HashMap::new();
+        for vertex in &mesh.vertices {
+            lookup.entry(vertex.color).or_insert_with(|| {
+                colors.push(vertex.color);
+                colors.len() - 1
+            });
+        }
+
+        let side = ((colors.len() as f64).sqrt().ceil() as u32).max(1);
+        ObjPalette { colors, side, lookup }
+    }
+
+    // texel centre of a color, with v going up from the bottom row
+    fn uv(&self, index: usize) -> (f32, f32) {
+        let (x, y) = (index as u32 % self.side, index as u32 / self.side);
+        ((x as f32 + 0.5) / self.side as f32, (y as f32 + 0.5) / self.side as f32)
+    }
+}
+
+// OBJ is y-up, this turns the viewer's z-up worldspace into it so importers show the same orientation
+fn to_y_up(v: [f32; 3]) -> [f32; 3] {
+    // adding zero keeps negated zeroes from being written as -0
+    [v[0], v[2], -v[1] + 0.0]
+}
+
+/// Writes the mesh with a UV into the palette texture for every vertex, and face normals
+pub fn save_obj<W: Write>(mesh: &KV6MeshData, palette: &ObjPalette, mtl_name: &str, file: W) -> io::Result<()> {
+    let mut file = BufWriter::new(file);
+
+    writeln!(file, "mtllib {}", mtl_name)?;
+
+    for vertex in &mesh.vertices {
+        let [x, y, z] = to_y_up(vertex.position);
+        writeln!(file, "v {} {} {}", x, y, z)?;
+    }
+
+    for index in 0..palette.colors.len() {
+        let (u, v) = palette.uv(index);
+        writeln!(file, "vt {} {}", u, v)?;
+    }
+
+    let mut normals = Vec::new();
+    let mut normal_indices = Vec::with_capacity(mesh.vertices.len());
+    for vertex in &mesh.vertices {
+        let face = to_y_up(vertex.face);
+        let index = match normals.iter().position(|&normal| normal == face) {
+            Some(index) => index,
+            None => {
+                writeln!(file, "vn {} {} {}", face[0], face[1], face[2])?;
+                normals.push(face);
+                normals.len() - 1
+            }
+        };
+        normal_indices.push(index);
+    }
+
+    writeln!(file, "usemtl palette")?;
+    for triangle in mesh.indices.chunks_exact(3) {
+        write!(file, "f")?;
+        for &index in triangle {
+            let index = index as usize;
+            // OBJ counts from one
+            let uv = palette.lookup[&mesh.vertices[index].color];
+            write!(file, " {}/{}/{}", index + 1, uv + 1, normal_indices[index] + 1)?;
+        }
+        writeln!(file)?;
+    }
+
+    file.flush()
+}
+
+pub fn save_mtl<W: Write>(texture_name: &str, file: W) -> io::Result<()> {
+    let mut file = BufWriter::new(file);
+    writeln!(file, "newmtl palette")?;
+    writeln!(file, "Ka 0 0 0")?;
+    writeln!(file, "Kd 1 1 1")?;
+    writeln!(file, "Ks 0 0 0")?;
+    writeln!(file, "map_Kd {}", texture_name)?;
+    file.flush()
+}
+
+/// Writes the palette texture as an uncompressed TGA, bottom row first
+pub fn save_palette_tga<W: Write>(palette: &ObjPalette, file: W) -> io::Result<()> {
+    let mut file = BufWriter::new(file);
+
+    file.write_u8(0)?; // no image id
+    file.write_u8(0)?; // no color map
+    file.write_u8(2)?; // uncompressed true color
+    file.write_all(&[0; 5])?; // color map spec
+    file.write_u16::<LittleEndian>(0)?; // x origin
+    file.write_u16::<LittleEndian>(0)?; // y origin
+    file.write_u16::<LittleEndian>(palette.side as u16)?;
+    file.write_u16::<LittleEndian>(palette.side as u16)?;
+    file.write_u8(24)?;
+    file.write_u8(0)?; // origin at the bottom left
+
+    for i in 0..(palette.side * palette.side) as usize {
+        let [r, g, b] = palette.colors.get(i).copied().unwrap_or([0, 0, 0]);
+        file.write_all(&[b, g, r])?;
+    }
+
+    file.flush()
+}
+
+/// Writes `path` along with its material and palette texture, named after it with .mtl and .tga extensions
+pub fn export_obj(mesh: &KV6MeshData, path: &str) -> io::Result<()> {
+    let path = Path::new(path);
+    let mtl_path = path.with_extension("mtl");
+    let texture_path = path.with_extension("tga");
+
+    // the files refer to each other relative to where they are
+    let file_name = |path: &Path| path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+
+    let palette = ObjPalette::new(mesh);
+    save_obj(mesh, &palette, &file_name(&mtl_path), File::create(path)?)?;
+    save_mtl(&file_name(&texture_path), File::create(&mtl_path)?)?;
+    save_palette_tga(&palette, File::create(&texture_path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KV6Data;
+
+    #[test]
+    fn writes_every_vertex_and_triangle_with_its_color() {
+        let data = KV6Data::from_bytes(include_bytes!("../../kv6/caco.kv6")).unwrap();
+        let mesh = KV6MeshData::from_data(&data);
+        let palette = ObjPalette::new(&mesh);
+
+        let mut out = Vec::new();
+        save_obj(&mesh, &palette, "caco.mtl", &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        let lines = |kind: &str| text.lines().filter(|line| line.split(' ').next() == Some(kind)).count();
+        assert_eq!(lines("v"), mesh.vertices.len());
+        assert_eq!(lines("vt"), palette.colors.len());
+        // one normal per side of a voxel
+        assert_eq!(lines("vn"), 6);
+        assert_eq!(lines("f"), mesh.triangle_count());
+
+        for (triangle, line) in mesh.indices.chunks_exact(3).zip(text.lines().filter(|line| line.starts_with("f "))) {
+            for (&index, corner) in triangle.iter().zip(line.split(' ').skip(1)) {
+                let refs = corner.split('/').map(|i| i.parse::<usize>().unwrap()).collect::<Vec<_>>();
+                assert_eq!(refs[0], index as usize + 1);
+                assert_eq!(palette.colors[refs[1] - 1], mesh.vertices[index as usize].color);
+            }
+        }
+
+        let mut texture = Vec::new();
+        save_palette_tga(&palette, &mut texture).unwrap();
+        assert_eq!(texture.len(), 18 + (palette.side * palette.side * 3) as usize);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::KV6MeshData;
+
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// Writes a binary PLY with positions, outward KV6 normals and colors for every vertex.
+/// Triangles are only written if the mesh has any, so `KV6MeshData::voxel_points` gives a point cloud.
+pub fn save_ply<W: Write>(mesh: &KV6MeshData, file: W) -> io::Result<()> {
+    let mut file = BufWriter::new(file);
+
+    writeln!(file, "ply")?;
+    writeln!(file, "format binary_little_endian 1.0")?;
+    writeln!(file, "element vertex {}", mesh.vertices.len())?;
+    for property in &["x", "y", "z", "nx", "ny", "nz"] {
+        writeln!(file, "property float {}", property)?;
+    }
+    for property in &["red", "green", "blue"] {
+        writeln!(file, "property uchar {}", property)?;
+    }
+    if !mesh.indices.is_empty() {
+        writeln!(file, "element face {}", mesh.triangle_count())?;
+        writeln!(file, "property list uchar uint vertex_indices")?;
+    }
+    writeln!(file, "end_header")?;
+
+    for vertex in &mesh.vertices {
+        for &value in vertex.position.iter().chain(vertex.outward_normal().iter()) {
+            file.write_f32::<LittleEndian>(value)?;
+        }
+        file.write_all(&vertex.color)?;
+    }
+
+    for triangle in mesh.indices.chunks_exact(3) {
+        file.write_u8(3)?;
+        for &index in triangle {
+            file.write_u32::<LittleEndian>(index)?;
+        }
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KV6Data;
+
+    // the header's lines, and the bytes after it
+    fn split_header(bytes: &[u8]) -> (Vec<String>, &[u8]) {
+        let end = bytes.windows(11).position(|window| window == b"end_header\n").unwrap() + 11;
+        let header = String::from_utf8(bytes[..end].to_vec()).unwrap();
+        (header.lines().map(str::to_string).collect(), &bytes[end..])
+    }
+
+    #[test]
+    fn writes_meshes_and_point_clouds() {
+        let data = KV6Data::from_bytes(include_bytes!("../../kv6/caco.kv6")).unwrap();
+
+        let mesh = KV6MeshData::from_data(&data);
+        let mut bytes = Vec::new();
+        save_ply(&mesh, &mut bytes).unwrap();
+
+        let (header, body) = split_header(&bytes);
+        assert!(header.contains(&format!("element vertex {}", mesh.vertices.len())));
+        assert!(header.contains(&format!("element face {}", mesh.triangle_count())));
+        // six floats and three colors a vertex, a count and three indices a triangle
+        assert_eq!(body.len(), mesh.vertices.len() * 27 + mesh.triangle_count() * 13);
+
+        let points = KV6MeshData::voxel_points(&data);
+        let mut bytes = Vec::new();
+        save_ply(&points, &mut bytes).unwrap();
+
+        let (header, body) = split_header(&bytes);
+        assert!(header.contains(&format!("element vertex {}", data.voxels.len())));
+        assert!(!header.iter().any(|line| line.starts_with("element face")));
+        assert_eq!(body.len(), data.voxels.len() * 27);
+    }
+}
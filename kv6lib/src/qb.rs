@@ -0,0 +1,553 @@
+use crate::kv6data::read_remaining;
+use crate::{KV6Color, KV6Data, KV6Limits, VoxelGrid};
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::BufWriter;
+use std::io::Write;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use cgmath::Vector3;
+
+const QB_VERSION: u32 = 0x0000_0101;
+// version, color format, z axis orientation, compression, visibility mask encoding and matrix count
+const QB_HEADER_SIZE: usize = 6 * 4;
+// in compressed matrices, a run of one color or the end of a z slice
+const CODE_FLAG: u32 = 2;
+const NEXT_SLICE_FLAG: u32 = 6;
+// a matrix's name length, size and position
+const MATRIX_HEADER_SIZE: usize = 1 + 6 * 4;
+// a scene spanning more than this is surely garbage, and would take too much memory to merge
+const MAX_SCENE_CELLS: u64 = 1 << 26;
+const MAX_POSITION: i32 = 1 << 16;
+
+/// A named block of voxels from a Qubicle file. Always stored left-handed,
+/// as x right, y up and z forward, whatever the file it came from used.
+pub struct QBMatrix {
+    pub name: String,
+    pub size: Vector3<u32>,
+    pub position: Vector3<i32>,
+    // RGB, indexed x first, then y, then z
+    pub voxels: Vec<Option<[u8; 3]>>,
+}
+
+pub struct QBScene {
+    pub matrices: Vec<QBMatrix>,
+}
+
+/// How `save_qb` lays out the file, Qubicle reads all of them
+#[derive(Debug, Copy, Clone)]
+pub struct QBOptions {
+    pub compressed: bool,
+    pub right_handed: bool,
+}
+
+impl Default for QBOptions {
+    fn default() -> QBOptions {
+        QBOptions {
+            compressed: true,
+            right_handed: false
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum QBError {
+    Io(io::Error),
+    BadVersion { found: u32 },
+    Truncated { offset: u64 },
+    BadMatrix { offset: u64, size: Vector3<u32> },
+    BadSlice { offset: u64, z: u32 },
+    TooLarge { size: Vector3<u64> },
+    TooManyCells { offset: u64, max_cells: u64 },
+}
+
+impl fmt::Display for QBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QBError::Io(err) =>
+                write!(f, "{}", err),
+            QBError::BadVersion { found } =>
+                write!(f, "not a Qubicle file: expected version 0x{:08x}, found 0x{:08x}", QB_VERSION, found),
+            QBError::Truncated { offset } =>
+                write!(f, "QB file ends early at byte {}", offset),
+            QBError::BadMatrix { offset, size } =>
+                write!(f, "invalid matrix size {}x{}x{} at byte {}", size.x, size.y, size.z, offset),
+            QBError::BadSlice { offset, z } =>
+                write!(f, "compressed slice {} at byte {} holds more voxels than the matrix", z, offset),
+            QBError::TooLarge { size } =>
+                write!(f, "scene spans {}x{}x{} voxels, which is too large to load", size.x, size.y, size.z),
+            QBError::TooManyCells { offset, max_cells } =>
+                write!(f, "matrix at byte {} takes the scene over the limit of {} cells", offset, max_cells),
+        }
+    }
+}
+
+impl std::error::Error for QBError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QBError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for QBError {
+    fn from(err: io::Error) -> QBError {
+        QBError::Io(err)
+    }
+}
+
+pub type QBResult<T> = std::result::Result<T, QBError>;
+
+fn read_u32(bytes: &[u8], offset: usize) -> QBResult<u32> {
+    bytes.get(offset..offset + 4)
+        .map(LittleEndian::read_u32)
+        .ok_or(QBError::Truncated { offset: bytes.len() as u64 })
+}
+
+impl QBMatrix {
+    fn index(&self, x: u32, y: u32, z: u32) -> usize {
+        (z as usize * self.size.y as usize + y as usize) * self.size.x as usize + x as usize
+    }
+
+    pub fn voxel(&self, x: u32, y: u32, z: u32) -> Option<[u8; 3]> {
+        self.voxels[self.index(x, y, z)]
+    }
+}
+
+impl QBScene {
+    pub fn from_reader<R: Read + Seek>(file: R) -> QBResult<QBScene> {
+        QBScene::from_reader_with_limits(file, &KV6Limits::default())
+    }
+
+    /// Like `from_reader`, but with caller-chosen limits. Every matrix is held to `max_dimension`,
+    /// and all of them together to `max_cells`, before anything is allocated for them.
+    pub fn from_reader_with_limits<R: Read + Seek>(mut file: R, limits: &KV6Limits) -> QBResult<QBScene> {
+        let bytes = read_remaining(&mut file)?;
+
+        QBScene::from_bytes_with_limits(&bytes, limits)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> QBResult<QBScene> {
+        QBScene::from_bytes_with_limits(bytes, &KV6Limits::default())
+    }
+
+    pub fn from_bytes_with_limits(bytes: &[u8], limits: &KV6Limits) -> QBResult<QBScene> {
+        let version = read_u32(bytes, 0)?;
+        if version != QB_VERSION {
+            return Err(QBError::BadVersion { found: version });
+        }
+
+        let bgra = read_u32(bytes, 4)? == 1;
+        let right_handed = read_u32(bytes, 8)? == 1;
+        let compressed = read_u32(bytes, 12)? != 0;
+        // with the visibility mask encoded the alpha holds which sides are visible instead,
+        // either way it's only zero for empty voxels
+        let matrix_count = read_u32(bytes, 20)?;
+
+        let color = |value: u32| {
+            let [a, b, c, alpha] = value.to_le_bytes();
+            match (alpha, bgra) {
+                (0, _) => None,
+                (_, true) => Some([c, b, a]),
+                (_, false) => Some([a, b, c])
+            }
+        };
+
+        // every matrix takes up at least its header
+        if matrix_count as usize > bytes.len().saturating_sub(QB_HEADER_SIZE) / MATRIX_HEADER_SIZE {
+            return Err(QBError::Truncated { offset: bytes.len() as u64 });
+        }
+
+        let mut matrices = Vec::new();
+        let mut offset = QB_HEADER_SIZE;
+        let mut total_cells = 0;
+
+        for _ in 0..matrix_count {
+            let name_length = *bytes.get(offset)
+                .ok_or(QBError::Truncated { offset: bytes.len() as u64 })? as usize;
+            let name = bytes.get(offset + 1..offset + 1 + name_length)
+                .ok_or(QBError::Truncated { offset: bytes.len() as u64 })?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            offset += 1 + name_length;
+
+            let size = Vector3::new(read_u32(bytes, offset)?, read_u32(bytes, offset + 4)?, read_u32(bytes, offset + 8)?);
+            let mut position = Vector3::new(
+                read_u32(bytes, offset + 12)? as i32,
+                read_u32(bytes, offset + 16)? as i32,
+                read_u32(bytes, offset + 20)? as i32
+            );
+
+            // sizes are checked first so multiplying them can't overflow
+            let too_far = [position.x, position.y, position.z].iter().any(|v| v.unsigned_abs() > MAX_POSITION as u32);
+            if size.x.max(size.y).max(size.z) > limits.max_dimension || too_far {
+                return Err(QBError::BadMatrix { offset: offset as u64, size });
+            }
+            let cells = size.x as u64 * size.y as u64 * size.z as u64;
+            total_cells += cells;
+            if total_cells > limits.max_cells {
+                return Err(QBError::TooManyCells { offset: offset as u64, max_cells: limits.max_cells });
+            }
+            offset += 24;
+
+            // a compressed slice takes at least its end marker, an uncompressed one four bytes a cell
+            let min_bytes = if compressed { size.z as u64 * 4 } else { cells * 4 };
+            if min_bytes > bytes.len().saturating_sub(offset) as u64 {
+                return Err(QBError::Truncated { offset: bytes.len() as u64 });
+            }
+
+            // right-handed files count z the other way, the matrix is flipped over below so it's stored left-handed
+            if right_handed {
+                position.z = -position.z - size.z as i32;
+            }
+            let flip_z = |z: u32| if right_handed { size.z - 1 - z } else { z };
+
+            let mut matrix = QBMatrix {
+                name,
+                size,
+                position,
+                voxels: vec![None; cells as usize]
+            };
+
+            let slice_size = size.x as usize * size.y as usize;
+            for z in 0..size.z {
+                if compressed {
+                    let slice_offset = offset;
+                    let mut filled = 0;
+                    let mut slice = vec![None; slice_size];
+
+                    loop {
+                        let value = read_u32(bytes, offset)?;
+                        offset += 4;
+
+                        let (count, value) = match value {
+                            NEXT_SLICE_FLAG => break,
+                            CODE_FLAG => {
+                                let run = (read_u32(bytes, offset)? as usize, read_u32(bytes, offset + 4)?);
+                                offset += 8;
+                                run
+                            },
+                            _ => (1, value)
+                        };
+
+                        if count > slice_size - filled {
+                            return Err(QBError::BadSlice { offset: slice_offset as u64, z });
+                        }
+                        for cell in &mut slice[filled..filled + count] {
+                            *cell = color(value);
+                        }
+                        filled += count;
+                    }
+
+                    let start = matrix.index(0, 0, flip_z(z));
+                    matrix.voxels[start..start + slice_size].copy_from_slice(&slice);
+                } else {
+                    let slice = bytes.get(offset..offset + slice_size * 4)
+                        .ok_or(QBError::Truncated { offset: bytes.len() as u64 })?;
+                    offset += slice_size * 4;
+
+                    let start = matrix.index(0, 0, flip_z(z));
+                    for (cell, value) in matrix.voxels[start..start + slice_size].iter_mut().zip(slice.chunks_exact(4)) {
+                        *cell = color(LittleEndian::read_u32(value));
+                    }
+                }
+            }
+
+            matrices.push(matrix);
+        }
+
+        Ok(QBScene { matrices })
+    }
+
+    // a grid covering the given matrices, KV6 z goes down where Qubicle's y goes up
+    fn to_grid(&self, matrices: &[&QBMatrix]) -> QBResult<(VoxelGrid, Vector3<i32>)> {
+        let mut min = Vector3::new(i32::MAX, i32::MAX, i32::MAX);
+        let mut max = Vector3::new(i32::MIN, i32::MIN, i32::MIN);
+        for matrix in matrices {
+            let end = matrix.position + Vector3::new(matrix.size.x as i32, matrix.size.y as i32, matrix.size.z as i32);
+            min = Vector3::new(min.x.min(matrix.position.x), min.y.min(matrix.position.y), min.z.min(matrix.position.z));
+            max = Vector3::new(max.x.max(end.x), max.y.max(end.y), max.z.max(end.z));
+        }
+
+        if min.x >= max.x || min.y >= max.y || min.z >= max.z {
+            return Ok((VoxelGrid::new(Vector3::new(0, 0, 0), Vector3::new(0.0, 0.0, 0.0)), Vector3::new(0, 0, 0)));
+        }
+
+        let extent = Vector3::new(
+            (max.x as i64 - min.x as i64) as u64,
+            (max.y as i64 - min.y as i64) as u64,
+            (max.z as i64 - min.z as i64) as u64
+        );
+        if extent.x.saturating_mul(extent.y).saturating_mul(extent.z) > MAX_SCENE_CELLS {
+            return Err(QBError::TooLarge { size: extent });
+        }
+
+        let size = Vector3::new(extent.x as u32, extent.z as u32, extent.y as u32);
+        let mut grid = VoxelGrid::new(size, Vector3::new(size.x as f32 / 2.0, size.y as f32 / 2.0, size.z as f32 / 2.0));
+
+        for matrix in matrices {
+            let offset = matrix.position - min;
+            for z in 0..matrix.size.z {
+                for y in 0..matrix.size.y {
+                    for x in 0..matrix.size.x {
+                        if let Some([r, g, b]) = matrix.voxel(x, y, z) {
+                            let (x, y, z) = (offset.x + x as i32, offset.y + y as i32, offset.z + z as i32);
+                            // swapping y and z also turns left-handed into right-handed, x is flipped to match z
+                            grid.set(size.x as i32 - 1 - x, z, size.z as i32 - 1 - y, Some(KV6Color { b, g, r, a: 128 }));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok((grid, min))
+    }
+
+    /// Every matrix merged into one KV6 model, with visibility and normals worked out from its shape
+    pub fn to_kv6(&self) -> QBResult<KV6Data> {
+        let matrices = self.matrices.iter().collect::<Vec<_>>();
+        Ok(self.to_grid(&matrices)?.0.to_kv6())
+    }
+
+    /// Each matrix as its own KV6 model, along with where to draw it in worldspace
+    /// so that they all fit together around the origin
+    pub fn to_kv6_models(&self) -> QBResult<Vec<(KV6Data, Vector3<f32>)>> {
+        let matrices = self.matrices.iter().collect::<Vec<_>>();
+        let (scene, scene_min) = self.to_grid(&matrices)?;
+        let center = Vector3::new(
+            scene_min.x as f32 + scene.size.x as f32 / 2.0,
+            scene_min.z as f32 + scene.size.y as f32 / 2.0,
+            scene_min.y as f32 + scene.size.z as f32 / 2.0
+        );
+
+        let mut models = Vec::new();
+        for matrix in &self.matrices {
+            let (grid, min) = self.to_grid(&[matrix])?;
+            let data = grid.to_kv6();
+
+            // undoes where the mesh puts the matrix's corner, see `KV6MeshData::from_data`
+            let (size, pivot) = (data.size, data.pivot);
+            let corner = Vector3::new(pivot.x - size.x as f32 + 1.0, -pivot.y, -pivot.z - size.z as f32 + 1.0);
+            let position = Vector3::new(min.x as f32, min.z as f32, min.y as f32) - center - corner;
+
+            models.push((data, position));
+        }
+
+        Ok(models)
+    }
+
+    /// Fills in the model's interior and stores it as a single matrix,
+    /// placed so that the model's pivot ends up at the origin
+    pub fn from_kv6(data: &KV6Data, name: &str) -> QBScene {
+        let grid = VoxelGrid::from(data);
+        let size = Vector3::new(grid.size.x, grid.size.z, grid.size.y);
+
+        let mut matrix = QBMatrix {
+            name: name.to_string(),
+            size,
+            position: Vector3::new(
+                -(grid.size.x as f32 - grid.pivot.x).round() as i32,
+                -(grid.size.z as f32 - grid.pivot.z).round() as i32,
+                -grid.pivot.y.round() as i32
+            ),
+            voxels: vec![None; size.x as usize * size.y as usize * size.z as usize]
+        };
+
+        for z in 0..size.z {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let color = grid.color((size.x - 1 - x) as i32, z as i32, (size.y - 1 - y) as i32);
+                    let i = matrix.index(x, y, z);
+                    matrix.voxels[i] = color.map(|color| [color.r, color.g, color.b]);
+                }
+            }
+        }
+
+        QBScene { matrices: vec![matrix] }
+    }
+}
+
+// one z slice, with runs of the same value longer than two cells compressed
+fn write_compressed_slice(out: &mut Vec<u8>, slice: &[u32]) -> io::Result<()> {
+    let mut i = 0;
+    while i < slice.len() {
+        let value = slice[i];
+        let count = slice[i..].iter().take_while(|&&other| other == value).count();
+
+        if count > 2 {
+            out.write_u32::<LittleEndian>(CODE_FLAG)?;
+            out.write_u32::<LittleEndian>(count as u32)?;
+            out.write_u32::<LittleEndian>(value)?;
+        } else {
+            for _ in 0..count {
+                out.write_u32::<LittleEndian>(value)?;
+            }
+        }
+        i += count;
+    }
+    out.write_u32::<LittleEndian>(NEXT_SLICE_FLAG)
+}
+
+pub fn load_qb(path: &str) -> QBResult<QBScene> {
+    QBScene::from_reader(BufReader::new(File::open(path)?))
+}
+
+/// Writes the scene with RGBA colors and no visibility mask
+pub fn save_qb<W: Write>(scene: &QBScene, options: QBOptions, file: W) -> io::Result<()> {
+    let mut out = Vec::new();
+    out.write_u32::<LittleEndian>(QB_VERSION)?;
+    out.write_u32::<LittleEndian>(0)?; // RGBA
+    out.write_u32::<LittleEndian>(options.right_handed as u32)?;
+    out.write_u32::<LittleEndian>(options.compressed as u32)?;
+    out.write_u32::<LittleEndian>(0)?; // no visibility mask
+    out.write_u32::<LittleEndian>(scene.matrices.len() as u32)?;
+
+    for matrix in &scene.matrices {
+        // names longer than this don't fit the length byte
+        let name = &matrix.name.as_bytes()[..matrix.name.len().min(255)];
+        out.write_u8(name.len() as u8)?;
+        out.write_all(name)?;
+
+        let mut position = matrix.position;
+        if options.right_handed {
+            position.z = -position.z - matrix.size.z as i32;
+        }
+
+        for &value in &[matrix.size.x, matrix.size.y, matrix.size.z] {
+            out.write_u32::<LittleEndian>(value)?;
+        }
+        for &value in &[position.x, position.y, position.z] {
+            out.write_i32::<LittleEndian>(value)?;
+        }
+
+        for z in 0..matrix.size.z {
+            let z = if options.right_handed { matrix.size.z - 1 - z } else { z };
+            let start = matrix.index(0, 0, z);
+            let slice = matrix.voxels[start..start + matrix.size.x as usize * matrix.size.y as usize].iter()
+                .map(|voxel| voxel.map_or(0, |[r, g, b]| u32::from_le_bytes([r, g, b, 0xff])))
+                .collect::<Vec<_>>();
+
+            if options.compressed {
+                write_compressed_slice(&mut out, &slice)?;
+            } else {
+                for value in slice {
+                    out.write_u32::<LittleEndian>(value)?;
+                }
+            }
+        }
+    }
+
+    let mut file = BufWriter::new(file);
+    file.write_all(&out)?;
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODELS: [(&str, &[u8]); 3] = [
+        ("caco", include_bytes!("../../kv6/caco.kv6")),
+        ("desklamp", include_bytes!("../../kv6/desklamp.kv6")),
+        ("light", include_bytes!("../../kv6/light.kv6")),
+    ];
+
+    fn voxels(data: &KV6Data) -> Vec<(u32, u32, u16, [u8; 3], u8)> {
+        data.iter_voxels()
+            .map(|(x, y, z, voxel)| (x, y, z, [voxel.color.r, voxel.color.g, voxel.color.b], voxel.visibility))
+            .collect()
+    }
+
+    // a scene with a single matrix called "m" whose header holds these numbers
+    fn matrix_header(size: [u32; 3], position: [u32; 3]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &value in &[QB_VERSION, 0, 0, 0, 0, 1] {
+            bytes.write_u32::<LittleEndian>(value).unwrap();
+        }
+        bytes.extend_from_slice(&[1, b'm']);
+        for &value in size.iter().chain(position.iter()) {
+            bytes.write_u32::<LittleEndian>(value).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn rejects_matrix_sizes_that_overflow() {
+        let bytes = matrix_header([u32::MAX, u32::MAX, u32::MAX], [0, 0, 0]);
+        assert!(matches!(QBScene::from_bytes(&bytes), Err(QBError::BadMatrix { offset: 26, .. })));
+    }
+
+    #[test]
+    fn rejects_positions_out_of_range() {
+        let bytes = matrix_header([1, 1, 1], [i32::MIN as u32, 0, 0]);
+        assert!(matches!(QBScene::from_bytes(&bytes), Err(QBError::BadMatrix { offset: 26, .. })));
+    }
+
+    #[test]
+    fn rejects_more_matrices_than_the_file_holds() {
+        let mut bytes = matrix_header([1, 1, 1], [0, 0, 0]);
+        bytes[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(QBScene::from_bytes(&bytes), Err(QBError::Truncated { .. })));
+    }
+
+    #[test]
+    fn rejects_more_slices_than_the_file_holds() {
+        let mut bytes = matrix_header([1, 1, 4096], [0, 0, 0]);
+        bytes[12..16].copy_from_slice(&1u32.to_le_bytes());
+        bytes.write_u32::<LittleEndian>(NEXT_SLICE_FLAG).unwrap();
+        assert!(matches!(QBScene::from_bytes(&bytes), Err(QBError::Truncated { .. })));
+    }
+
+    #[test]
+    fn bounds_the_cells_of_all_matrices_together() {
+        // 40 compressed 4096x4096x4 matrices with every slice left empty, in 1704 bytes
+        let mut bytes = Vec::new();
+        for &value in &[QB_VERSION, 0, 0, 1, 0, 40] {
+            bytes.write_u32::<LittleEndian>(value).unwrap();
+        }
+        for _ in 0..40 {
+            bytes.extend_from_slice(&[1, b'm']);
+            for &value in &[4096, 4096, 4, 0, 0, 0] {
+                bytes.write_u32::<LittleEndian>(value).unwrap();
+            }
+            for _ in 0..4 {
+                bytes.write_u32::<LittleEndian>(NEXT_SLICE_FLAG).unwrap();
+            }
+        }
+        assert_eq!(bytes.len(), 1704);
+
+        assert!(matches!(QBScene::from_bytes(&bytes), Err(QBError::TooManyCells { offset: 26, .. })));
+
+        // one matrix fits these limits, the second doesn't
+        let limits = KV6Limits { max_cells: 1 << 26, ..KV6Limits::default() };
+        assert!(matches!(QBScene::from_bytes_with_limits(&bytes, &limits), Err(QBError::TooManyCells { offset: 68, .. })));
+    }
+
+    #[test]
+    fn saved_models_load_back_the_same() {
+        for (name, bytes) in MODELS.iter() {
+            let data = KV6Data::from_bytes(bytes).unwrap();
+            let scene = QBScene::from_kv6(&data, name);
+
+            for &(compressed, right_handed) in &[(true, false), (false, false), (true, true)] {
+                let mut saved = Vec::new();
+                save_qb(&scene, QBOptions { compressed, right_handed }, &mut saved).unwrap();
+
+                let loaded = QBScene::from_bytes(&saved).unwrap();
+                assert_eq!(loaded.matrices.len(), 1, "{}", name);
+                assert_eq!(loaded.matrices[0].name, *name);
+                assert_eq!(loaded.matrices[0].position, scene.matrices[0].position, "{}", name);
+                assert!(loaded.matrices[0].voxels == scene.matrices[0].voxels, "{} changed", name);
+
+                let reloaded = loaded.to_kv6().unwrap();
+                assert_eq!(reloaded.size, data.size, "{}", name);
+                assert!(voxels(&reloaded) == voxels(&data), "{} changed", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::KV6Color;
+
+use std::collections::HashMap;
+
+/// Maps colors to their closest entry in a list, remembering colors it has seen before
+pub struct Quantizer {
+    colors: Vec<[u8; 3]>,
+    cache: HashMap<[u8; 3], usize>
+}
+
+impl Quantizer {
+    pub fn new(colors: Vec<[u8; 3]>) -> Quantizer {
+        Quantizer {
+            colors,
+            cache: HashMap::new()
+        }
+    }
+
+    pub fn index(&mut self, color: KV6Color) -> usize {
+        let rgb = [color.r, color.g, color.b];
+        let colors = &self.colors;
+        *self.cache.entry(rgb).or_insert_with(|| {
+            let distance = |entry: &[u8; 3]| -> i32 {
+                entry.iter().zip(rgb.iter()).map(|(&a, &b)| (a as i32 - b as i32).pow(2)).sum()
+            };
+            (0..colors.len()).min_by_key(|&i| distance(&colors[i])).unwrap()
+        })
+    }
+}
+
+/// Reduces a histogram of colors to at most `max_colors` by repeatedly splitting
+/// the box of colors with the widest range at its median
+pub fn median_cut(counts: HashMap<[u8; 3], u32>, max_colors: usize) -> Vec<[u8; 3]> {
+    let mut boxes = vec![counts.into_iter().collect::<Vec<_>>()];
+    if boxes[0].is_empty() {
+        return Vec::new();
+    }
+
+    while boxes.len() < max_colors {
+        let widest = boxes.iter().enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(i, colors)| {
+                let (component, range) = (0..3).map(|c| {
+                    let min = colors.iter().map(|(rgb, _)| rgb[c]).min().unwrap();
+                    let max = colors.iter().map(|(rgb, _)| rgb[c]).max().unwrap();
+                    (c, max - min)
+                }).max_by_key(|&(_, range)| range).unwrap();
+                (i, component, range)
+            })
+            .max_by_key(|&(_, _, range)| range);
+
+        let (i, component) = match widest {
+            Some((i, component, _)) => (i, component),
+            None => break
+        };
+
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_by_key(|(rgb, _)| rgb[component]);
+
+        // split at the median by count, keeping at least one color on each side
+        let total: u32 = colors.iter().map(|(_, count)| count).sum();
+        let mut seen = 0;
+        let mut split = 1;
+        for (j, (_, count)) in colors.iter().enumerate() {
+            seen += count;
+            if seen * 2 >= total {
+                split = (j + 1).max(1).min(colors.len() - 1);
+                break;
+            }
+        }
+
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(|colors| {
+        let total: u32 = colors.iter().map(|(_, count)| count).sum();
+        let mut average = [0; 3];
+        for (c, component) in average.iter_mut().enumerate() {
+            let sum: u32 = colors.iter().map(|(rgb, count)| rgb[c] as u32 * count).sum();
+            *component = ((sum + total / 2) / total) as u8;
+        }
+        average
+    }).collect()
+}
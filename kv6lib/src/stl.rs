@@ -0,0 +1,315 @@
+use crate::KV6Data;
+use crate::voxelgrid::{Occupancy, VoxelGrid, NEIGHBOURS};
+
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use cgmath::Vector3;
+
+pub struct StlOptions {
+    pub binary: bool,
+    /// Size of one voxel in the output, slicers read STL units as millimetres
+    pub mm_per_voxel: f32,
+}
+
+impl Default for StlOptions {
+    fn default() -> StlOptions {
+        StlOptions {
+            binary: true,
+            mm_per_voxel: 1.0
+        }
+    }
+}
+
+// solid cells only, colors don't matter for printing
+struct SolidGrid {
+    size: Vector3<i32>,
+    solid: Vec<bool>,
+}
+
+impl SolidGrid {
+    fn index(&self, x: i32, y: i32, z: i32) -> Option<usize> {
+        if x < 0 || y < 0 || z < 0 || x >= self.size.x || y >= self.size.y || z >= self.size.z {
+            return None;
+        }
+
+        Some((x as usize * self.size.y as usize + y as usize) * self.size.z as usize + z as usize)
+    }
+
+    // looks for cells that only touch along an edge or at a corner in the 2x2x2 block starting at x, y, z,
+    // and fills one of the air cells in between. Returns whether anything was filled.
+    fn fix_block(&mut self, x: i32, y: i32, z: i32) -> bool {
+        // bit 0 of a cell is its x offset in the block, bit 1 y and bit 2 z
+        let cell = |i: usize| (x + (i & 1) as i32, y + (i >> 1 & 1) as i32, z + (i >> 2 & 1) as i32);
+        let solid: Vec<bool> = (0..8).map(|i| {
+            let (x, y, z) = cell(i);
+            self.is_solid(x, y, z)
+        }).collect();
+
+        let mut fill = None;
+
+        // two solid cells across the diagonal of a square with the other two air only share an edge
+        for i in 0..8 {
+            for &(a, b) in &[(0b001, 0b010), (0b001, 0b100), (0b010, 0b100)] {
+                if solid[i] && solid[i ^ a ^ b] && !solid[i ^ a] && !solid[i ^ b] {
+                    fill = Some(i ^ a);
+                }
+            }
+        }
+
+        // opposite corners of the block that are the only solid (or the only air) cells only share a vertex
+        if fill.is_none() {
+            let count = solid.iter().filter(|&&solid| solid).count();
+            for i in 0..4 {
+                if count == 2 && solid[i] && solid[i ^ 7] {
+                    // fills next to one of them, which leaves an edge contact for the next pass
+                    fill = Some(i ^ 1);
+                } else if count == 6 && !solid[i] && !solid[i ^ 7] {
+                    fill = Some(i);
+                }
+            }
+        }
+
+        match fill {
+            Some(i) => {
+                // the cells in between always lie inside the model's bounds
+                let (x, y, z) = cell(i);
+                let index = self.index(x, y, z).unwrap();
+                self.solid[index] = true;
+                true
+            }
+            None => false
+        }
+    }
+}
+
+impl Occupancy for SolidGrid {
+    fn is_solid(&self, x: i32, y: i32, z: i32) -> bool {
+        self.index(x, y, z).is_some_and(|i| self.solid[i])
+    }
+}
+
+/// Closed, manifold surface around a model's solid voxels, for printing.
+/// Triangles are in voxel units with z up and the model's bounds starting at the origin.
+pub struct StlShell {
+    pub triangles: Vec<[[i32; 3]; 3]>,
+    /// Air cells that had to be made solid so no voxels only touch along an edge or at a corner
+    pub filled: usize,
+}
+
+impl StlShell {
+    pub fn from_data(data: &KV6Data) -> StlShell {
+        let voxels = VoxelGrid::from(data);
+        let size = data.size.cast::<i32>().unwrap();
+
+        let mut grid = SolidGrid { size, solid: Vec::with_capacity(size.x as usize * size.y as usize * size.z as usize) };
+        for x in 0..size.x {
+            for y in 0..size.y {
+                for z in 0..size.z {
+                    grid.solid.push(voxels.is_solid(x, y, z));
+                }
+            }
+        }
+
+        // blocks hanging over the bounds by one so contacts with the outside get checked too,
+        // filling only ever adds, so this stops once a pass changes nothing
+        let mut filled = 0;
+        loop {
+            let mut changed = 0;
+            for x in -1..size.x {
+                for y in -1..size.y {
+                    for z in -1..size.z {
+                        if grid.fix_block(x, y, z) {
+                            changed += 1;
+                        }
+                    }
+                }
+            }
+
+            if changed == 0 {
+                break;
+            }
+            filled += changed;
+        }
+
+        // same orientation as the viewer shows it, KV6 z goes down
+        let to_print = |x: i32, y: i32, z: i32| [size.x - x, y, size.z - z];
+
+        let mut triangles = Vec::new();
+        for x in 0..size.x {
+            for y in 0..size.y {
+                for z in 0..size.z {
+                    if !grid.is_solid(x, y, z) {
+                        continue;
+                    }
+
+                    for &(dx, dy, dz, _) in NEIGHBOURS.iter() {
+                        if grid.is_solid(x + dx, y + dy, z + dz) {
+                            continue;
+                        }
+
+                        // corners of the face on the side of the cell facing that neighbour, going around it
+                        let normal = [dx, dy, dz];
+                        let axis = normal.iter().position(|&d| d != 0).unwrap();
+                        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                        let mut corners = [[0; 3]; 4];
+                        for (corner, &(du, dv)) in corners.iter_mut().zip(&[(0, 0), (1, 0), (1, 1), (0, 1)]) {
+                            let mut position = [x, y, z];
+                            if normal[axis] > 0 {
+                                position[axis] += 1;
+                            }
+                            position[u] += du;
+                            position[v] += dv;
+                            *corner = to_print(position[0], position[1], position[2]);
+                        }
+
+                        // wind them counter clockwise seen from outside
+                        let outward = [-dx, dy, -dz];
+                        if dot(cross(corners[0], corners[1], corners[2]), outward) < 0 {
+                            corners.reverse();
+                        }
+
+                        triangles.push([corners[0], corners[1], corners[2]]);
+                        triangles.push([corners[2], corners[3], corners[0]]);
+                    }
+                }
+            }
+        }
+
+        StlShell { triangles, filled }
+    }
+}
+
+fn cross(a: [i32; 3], b: [i32; 3], c: [i32; 3]) -> [i32; 3] {
+    let (u, v) = ([b[0] - a[0], b[1] - a[1], b[2] - a[2]], [c[0] - a[0], c[1] - a[1], c[2] - a[2]]);
+    [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]]
+}
+
+fn dot(a: [i32; 3], b: [i32; 3]) -> i32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Writes the shell as binary or ASCII STL, scaled to millimetres
+pub fn save_stl<W: Write>(shell: &StlShell, options: &StlOptions, file: W) -> io::Result<()> {
+    let mut file = BufWriter::new(file);
+    let scale = |corner: [i32; 3]| [
+        corner[0] as f32 * options.mm_per_voxel,
+        corner[1] as f32 * options.mm_per_voxel,
+        corner[2] as f32 * options.mm_per_voxel
+    ];
+
+    // every face is axis aligned, so this is already unit length
+    let normal = |triangle: &[[i32; 3]; 3]| {
+        let n = cross(triangle[0], triangle[1], triangle[2]);
+        [n[0].signum() as f32, n[1].signum() as f32, n[2].signum() as f32]
+    };
+
+    if options.binary {
+        // the header can't start with "solid" or readers take it for ASCII
+        let mut header = [0u8; 80];
+        let text = b"kv6view-rs voxel shell";
+        header[..text.len()].copy_from_slice(text);
+        file.write_all(&header)?;
+        file.write_u32::<LittleEndian>(shell.triangles.len() as u32)?;
+
+        for triangle in &shell.triangles {
+            for &value in normal(triangle).iter() {
+                file.write_f32::<LittleEndian>(value)?;
+            }
+            for &corner in triangle {
+                for &value in scale(corner).iter() {
+                    file.write_f32::<LittleEndian>(value)?;
+                }
+            }
+            file.write_u16::<LittleEndian>(0)?; // attribute byte count
+        }
+    } else {
+        writeln!(file, "solid kv6")?;
+        for triangle in &shell.triangles {
+            let [nx, ny, nz] = normal(triangle);
+            writeln!(file, "  facet normal {} {} {}", nx, ny, nz)?;
+            writeln!(file, "    outer loop")?;
+            for &corner in triangle {
+                let [x, y, z] = scale(corner);
+                writeln!(file, "      vertex {} {} {}", x, y, z)?;
+            }
+            writeln!(file, "    endloop")?;
+            writeln!(file, "  endfacet")?;
+        }
+        writeln!(file, "endsolid kv6")?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KV6Color;
+    use std::collections::HashMap;
+
+    const MODELS: [(&str, &[u8]); 3] = [
+        ("caco", include_bytes!("../../kv6/caco.kv6")),
+        ("desklamp", include_bytes!("../../kv6/desklamp.kv6")),
+        ("light", include_bytes!("../../kv6/light.kv6")),
+    ];
+
+    // every edge has to be used once in each direction, by two triangles facing the same way
+    fn assert_edge_manifold(shell: &StlShell, name: &str) {
+        let mut edges = HashMap::new();
+        for triangle in &shell.triangles {
+            for i in 0..3 {
+                *edges.entry((triangle[i], triangle[(i + 1) % 3])).or_insert(0) += 1;
+            }
+        }
+
+        for (&(a, b), &count) in &edges {
+            assert_eq!(count, 1, "{}: edge {:?} to {:?} used {} times", name, a, b, count);
+            assert_eq!(edges.get(&(b, a)), Some(&1), "{}: edge {:?} to {:?} is open", name, a, b);
+        }
+    }
+
+    #[test]
+    fn bundled_models_give_closed_shells() {
+        for (name, bytes) in MODELS.iter() {
+            let data = KV6Data::from_bytes(bytes).unwrap();
+            let shell = StlShell::from_data(&data);
+            assert!(!shell.triangles.is_empty());
+            assert_edge_manifold(&shell, name);
+        }
+    }
+
+    #[test]
+    fn fills_in_edge_and_corner_contacts() {
+        let color = Some(KV6Color { b: 0, g: 0, r: 255, a: 128 });
+
+        // voxels meeting along an edge, then only at a corner
+        for &other in &[(1, 1, 0), (1, 1, 1)] {
+            let mut grid = VoxelGrid::new(Vector3::new(2, 2, 2), Vector3::new(1.0, 1.0, 1.0));
+            grid.set(0, 0, 0, color);
+            grid.set(other.0, other.1, other.2, color);
+
+            let shell = StlShell::from_data(&grid.to_kv6());
+            assert!(shell.filled > 0);
+            assert_edge_manifold(&shell, &format!("{:?}", other));
+        }
+    }
+
+    #[test]
+    fn writes_a_record_per_triangle() {
+        let data = KV6Data::from_bytes(MODELS[2].1).unwrap();
+        let shell = StlShell::from_data(&data);
+
+        let mut bytes = Vec::new();
+        save_stl(&shell, &StlOptions::default(), &mut bytes).unwrap();
+        assert_eq!(bytes.len(), 84 + shell.triangles.len() * 50);
+
+        let mut text = Vec::new();
+        save_stl(&shell, &StlOptions { binary: false, ..StlOptions::default() }, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(text.matches("facet normal").count(), shell.triangles.len());
+    }
+}
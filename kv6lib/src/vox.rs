@@ -0,0 +1,830 @@
+use crate::kv6data::read_remaining;
+use crate::{KV6Color, KV6Data, VoxelGrid};
+use crate::quantize::{median_cut, Quantizer};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::BufWriter;
+use std::io::Write;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use cgmath::Vector3;
+
+const VOX_MAGIC: [u8; 4] = *b"VOX ";
+const VOX_VERSION: i32 = 150;
+const CHUNK_HEADER_SIZE: usize = 12;
+// MagicaVoxel can't make anything bigger along any axis
+pub const MAX_MODEL_SIZE: u32 = 256;
+// keeps a scene with far flung models from asking for an enormous grid
+const MAX_SCENE_CELLS: u64 = 1 << 26;
+const MAX_TRANSLATION: i32 = 1 << 16;
+// a shape can place the same model any number of times, so these bound the work `to_grid` does
+const MAX_INSTANCES: usize = 1 << 16;
+const MAX_SCENE_VOXELS: u64 = 1 << 24;
+
+/// A 3x3 matrix holding only 0, 1 and -1, as stored in a transform node's `_r` attribute
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VoxRotation(pub [[i32; 3]; 3]);
+
+impl VoxRotation {
+    pub const IDENTITY: VoxRotation = VoxRotation([[1, 0, 0], [0, 1, 0], [0, 0, 1]]);
+
+    // bits 0-1 and 2-3 give the column of the non-zero entry in the first two rows,
+    // bits 4-6 flip the sign of each row
+    fn from_byte(byte: u8) -> Option<VoxRotation> {
+        let first = (byte & 3) as usize;
+        let second = (byte >> 2 & 3) as usize;
+        if first > 2 || second > 2 || first == second {
+            return None;
+        }
+        let third = 3 - first - second;
+
+        let mut rows = [[0; 3]; 3];
+        for (row, &column) in [first, second, third].iter().enumerate() {
+            rows[row][column] = if byte >> (4 + row) & 1 == 1 { -1 } else { 1 };
+        }
+        Some(VoxRotation(rows))
+    }
+
+    fn to_byte(self) -> u8 {
+        let column = |row: &[i32; 3]| row.iter().position(|&v| v != 0).unwrap_or(0) as u8;
+
+        let mut byte = column(&self.0[0]) | column(&self.0[1]) << 2;
+        for (row, values) in self.0.iter().enumerate() {
+            if values.iter().sum::<i32>() < 0 {
+                byte |= 1 << (4 + row);
+            }
+        }
+        byte
+    }
+
+    fn apply(&self, v: Vector3<i32>) -> Vector3<i32> {
+        let [a, b, c] = self.0;
+        Vector3::new(
+            a[0] * v.x + a[1] * v.y + a[2] * v.z,
+            b[0] * v.x + b[1] * v.y + b[2] * v.z,
+            c[0] * v.x + c[1] * v.y + c[2] * v.z
+        )
+    }
+
+    fn then(&self, parent: &VoxRotation) -> VoxRotation {
+        let mut rows = [[0; 3]; 3];
+        for (i, row) in rows.iter_mut().enumerate() {
+            for (j, cell) in row.iter_mut().enumerate() {
+                *cell = (0..3).map(|k| parent.0[i][k] * self.0[k][j]).sum();
+            }
+        }
+        VoxRotation(rows)
+    }
+}
+
+pub struct VoxModel {
+    pub size: Vector3<u32>,
+    // x, y, z and palette index, z points up
+    pub voxels: Vec<[u8; 4]>
+}
+
+/// A model placed in the scene, voxel `v` of the model ends up at
+/// `translation + rotation * (v - size / 2)`
+pub struct VoxInstance {
+    pub model: usize,
+    pub rotation: VoxRotation,
+    pub translation: Vector3<i32>
+}
+
+pub struct VoxScene {
+    pub models: Vec<VoxModel>,
+    pub instances: Vec<VoxInstance>,
+    // RGBA, indexed by the palette index stored with each voxel
+    pub palette: [[u8; 4]; 256]
+}
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(io::Error),
+    BadMagic { found: [u8; 4] },
+    Truncated { offset: u64 },
+    BadChunk { offset: u64, id: [u8; 4] },
+    BadSize { offset: u64, size: Vector3<u32> },
+    BadNode { offset: u64, node: i32 },
+    BadModel { model: usize },
+    TooLarge { size: Vector3<u64> },
+    TooManyInstances { instances: usize },
+    TooManyVoxels { voxels: u64 },
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoxError::Io(err) =>
+                write!(f, "{}", err),
+            VoxError::BadMagic { found } =>
+                write!(f, "not a MagicaVoxel file: expected magic \"VOX \", found {:?}", found),
+            VoxError::Truncated { offset } =>
+                write!(f, "VOX file ends early at byte {}", offset),
+            VoxError::BadChunk { offset, id } =>
+                write!(f, "malformed {} chunk at byte {}", String::from_utf8_lossy(id), offset),
+            VoxError::BadSize { offset, size } =>
+                write!(f, "invalid model size {}x{}x{} at byte {}", size.x, size.y, size.z, offset),
+            VoxError::BadNode { offset, node } =>
+                write!(f, "scene node {} referenced at byte {} is missing or already used elsewhere in the scene", node, offset),
+            VoxError::BadModel { model } =>
+                write!(f, "scene places model {}, which the file doesn't have", model),
+            VoxError::TooLarge { size } =>
+                write!(f, "scene spans {}x{}x{} voxels, which is too large to load", size.x, size.y, size.z),
+            VoxError::TooManyInstances { instances } =>
+                write!(f, "scene places {} models, more than the limit of {}", instances, MAX_INSTANCES),
+            VoxError::TooManyVoxels { voxels } =>
+                write!(f, "scene places {} voxels, more than the limit of {}", voxels, MAX_SCENE_VOXELS),
+        }
+    }
+}
+
+impl std::error::Error for VoxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VoxError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for VoxError {
+    fn from(err: io::Error) -> VoxError {
+        VoxError::Io(err)
+    }
+}
+
+pub type VoxResult<T> = std::result::Result<T, VoxError>;
+
+// MagicaVoxel's palette for files without an RGBA chunk, as 0xAABBGGRR
+fn default_palette() -> [[u8; 4]; 256] {
+    let mut palette = [[0; 4]; 256];
+    let steps = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    let ramp = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut i = 1;
+    for &r in steps.iter() {
+        for &g in steps.iter() {
+            for &b in steps.iter() {
+                // black comes at the end of the grey ramp instead
+                if i < 216 {
+                    palette[i] = [r, g, b, 0xff];
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    for channel in 0..4 {
+        for &value in ramp.iter() {
+            palette[i] = match channel {
+                0 => [value, 0, 0, 0xff],
+                1 => [0, value, 0, 0xff],
+                2 => [0, 0, value, 0xff],
+                _ => [value, value, value, 0xff]
+            };
+            i += 1;
+        }
+    }
+
+    palette
+}
+
+// bounds checked little endian reads over the whole file
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    end: usize
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, count: usize) -> VoxResult<&'a [u8]> {
+        if count > self.end - self.offset {
+            return Err(VoxError::Truncated { offset: self.end as u64 });
+        }
+        let slice = &self.bytes[self.offset..self.offset + count];
+        self.offset += count;
+        Ok(slice)
+    }
+
+    fn read_i32(&mut self) -> VoxResult<i32> {
+        Ok(LittleEndian::read_i32(self.take(4)?))
+    }
+
+    fn read_string(&mut self) -> VoxResult<String> {
+        let length = self.read_i32()? as u32 as usize;
+        Ok(String::from_utf8_lossy(self.take(length)?).into_owned())
+    }
+
+    fn read_dict(&mut self) -> VoxResult<HashMap<String, String>> {
+        let count = self.read_i32()? as u32;
+        let mut dict = HashMap::new();
+        for _ in 0..count {
+            let key = self.read_string()?;
+            let value = self.read_string()?;
+            dict.insert(key, value);
+        }
+        Ok(dict)
+    }
+}
+
+enum Node {
+    Transform { child: i32, rotation: VoxRotation, translation: Vector3<i32> },
+    Group { children: Vec<i32> },
+    Shape { models: Vec<usize> },
+}
+
+fn parse_transform(dict: &HashMap<String, String>) -> Option<(VoxRotation, Vector3<i32>)> {
+    let rotation = match dict.get("_r") {
+        Some(r) => VoxRotation::from_byte(r.trim().parse().ok()?)?,
+        None => VoxRotation::IDENTITY
+    };
+
+    let translation = match dict.get("_t") {
+        Some(t) => {
+            let values = t.split_whitespace().map(|v| v.parse().ok()).collect::<Option<Vec<i32>>>()?;
+            // anything further out than this is surely garbage, and would overflow once nested
+            if values.len() != 3 || values.iter().any(|v| v.abs() > MAX_TRANSLATION) {
+                return None;
+            }
+            Vector3::new(values[0], values[1], values[2])
+        },
+        None => Vector3::new(0, 0, 0)
+    };
+
+    Some((rotation, translation))
+}
+
+impl VoxScene {
+    pub fn from_bytes(bytes: &[u8]) -> VoxResult<VoxScene> {
+        VoxScene::from_reader(io::Cursor::new(bytes))
+    }
+
+    pub fn from_reader<R: Read + Seek>(mut file: R) -> VoxResult<VoxScene> {
+        let bytes = read_remaining(&mut file)?;
+
+        let mut cursor = Cursor { bytes: &bytes, offset: 0, end: bytes.len() };
+        let mut magic = [0; 4];
+        magic.copy_from_slice(cursor.take(4)?);
+        if magic != VOX_MAGIC {
+            return Err(VoxError::BadMagic { found: magic });
+        }
+        let _version = cursor.read_i32()?;
+
+        let mut models = Vec::new();
+        let mut size = None;
+        let mut palette = default_palette();
+        let mut nodes = HashMap::new();
+
+        // every other chunk is a child of MAIN, so they're walked one after another
+        // straight after MAIN's header and (empty) content
+        let main_offset = cursor.offset;
+        let mut main_id = [0; 4];
+        main_id.copy_from_slice(cursor.take(4)?);
+        let main_content_size = cursor.read_i32()? as u32 as usize;
+        let _children_size = cursor.read_i32()?;
+        if &main_id != b"MAIN" || main_content_size > bytes.len() - cursor.offset {
+            return Err(VoxError::BadChunk { offset: main_offset as u64, id: main_id });
+        }
+
+        let mut offset = cursor.offset + main_content_size;
+        while offset + CHUNK_HEADER_SIZE <= bytes.len() {
+            let mut header = Cursor { bytes: &bytes, offset, end: bytes.len() };
+            let mut id = [0; 4];
+            id.copy_from_slice(header.take(4)?);
+            let content_size = header.read_i32()? as u32 as usize;
+            let _children_size = header.read_i32()?;
+
+            let content_start = header.offset;
+            let content_end = content_start.checked_add(content_size)
+                .filter(|&end| end <= bytes.len())
+                .ok_or(VoxError::Truncated { offset: bytes.len() as u64 })?;
+            let mut chunk = Cursor { bytes: &bytes, offset: content_start, end: content_end };
+            let bad_chunk = || VoxError::BadChunk { offset: offset as u64, id };
+
+            match &id {
+                b"SIZE" => {
+                    let model_size = Vector3::new(chunk.read_i32()? as u32, chunk.read_i32()? as u32, chunk.read_i32()? as u32);
+                    if model_size.x > MAX_MODEL_SIZE || model_size.y > MAX_MODEL_SIZE || model_size.z > MAX_MODEL_SIZE {
+                        return Err(VoxError::BadSize { offset: offset as u64, size: model_size });
+                    }
+                    size = Some(model_size);
+                },
+                b"XYZI" => {
+                    let model_size = size.take().ok_or_else(bad_chunk)?;
+                    let count = chunk.read_i32()? as u32 as usize;
+                    let data = chunk.take(count.checked_mul(4).ok_or_else(bad_chunk)?)?;
+
+                    let mut voxels = Vec::with_capacity(count);
+                    for voxel in data.chunks(4) {
+                        if voxel[0] as u32 >= model_size.x || voxel[1] as u32 >= model_size.y || voxel[2] as u32 >= model_size.z {
+                            return Err(bad_chunk());
+                        }
+                        voxels.push([voxel[0], voxel[1], voxel[2], voxel[3]]);
+                    }
+                    models.push(VoxModel { size: model_size, voxels });
+                },
+                b"RGBA" => {
+                    let data = chunk.take(256 * 4)?;
+                    // entry i of the chunk is palette index i + 1
+                    for (i, color) in data.chunks(4).take(255).enumerate() {
+                        palette[i + 1].copy_from_slice(color);
+                    }
+                },
+                b"nTRN" => {
+                    let node = chunk.read_i32()?;
+                    let _attributes = chunk.read_dict()?;
+                    let child = chunk.read_i32()?;
+                    let _reserved = chunk.read_i32()?;
+                    let _layer = chunk.read_i32()?;
+                    let frames = chunk.read_i32()?;
+                    // only the first animation frame is shown
+                    let (rotation, translation) = if frames > 0 {
+                        parse_transform(&chunk.read_dict()?).ok_or_else(bad_chunk)?
+                    } else {
+                        (VoxRotation::IDENTITY, Vector3::new(0, 0, 0))
+                    };
+                    nodes.insert(node, (offset, Node::Transform { child, rotation, translation }));
+                },
+                b"nGRP" => {
+                    let node = chunk.read_i32()?;
+                    let _attributes = chunk.read_dict()?;
+                    let count = chunk.read_i32()? as u32;
+                    let mut children = Vec::new();
+                    for _ in 0..count {
+                        children.push(chunk.read_i32()?);
+                    }
+                    nodes.insert(node, (offset, Node::Group { children }));
+                },
+                b"nSHP" => {
+                    let node = chunk.read_i32()?;
+                    let _attributes = chunk.read_dict()?;
+                    let count = chunk.read_i32()? as u32;
+                    let mut shape_models = Vec::new();
+                    for _ in 0..count {
+                        shape_models.push(chunk.read_i32()? as u32 as usize);
+                        let _frame = chunk.read_dict()?;
+                    }
+                    nodes.insert(node, (offset, Node::Shape { models: shape_models }));
+                },
+                // materials, layers, cameras and so on don't affect the voxels
+                _ => ()
+            }
+
+            offset = content_end;
+        }
+
+        let mut instances = Vec::new();
+        if nodes.is_empty() {
+            // files from before the scene graph just have their models at the origin
+            for model in 0..models.len() {
+                instances.push(VoxInstance { model, rotation: VoxRotation::IDENTITY, translation: Vector3::new(0, 0, 0) });
+            }
+        } else {
+            instances = collect_instances(&nodes)?;
+        }
+
+        if let Some(instance) = instances.iter().find(|instance| instance.model >= models.len()) {
+            return Err(VoxError::BadModel { model: instance.model });
+        }
+
+        Ok(VoxScene { models, instances, palette })
+    }
+
+    /// Places every instance into one grid in KV6 space, z pointing down
+    pub fn to_grid(&self) -> VoxResult<VoxelGrid> {
+        if self.instances.len() > MAX_INSTANCES {
+            return Err(VoxError::TooManyInstances { instances: self.instances.len() });
+        }
+        let voxels: u64 = self.instances.iter().map(|instance| self.models[instance.model].voxels.len() as u64).sum();
+        if voxels > MAX_SCENE_VOXELS {
+            return Err(VoxError::TooManyVoxels { voxels });
+        }
+
+        let placed = |instance: &VoxInstance, voxel: &[u8; 4]| {
+            let size = self.models[instance.model].size;
+            let centered = Vector3::new(
+                voxel[0] as i32 - (size.x / 2) as i32,
+                voxel[1] as i32 - (size.y / 2) as i32,
+                voxel[2] as i32 - (size.z / 2) as i32
+            );
+            instance.translation + instance.rotation.apply(centered)
+        };
+
+        let mut min = Vector3::new(i32::MAX, i32::MAX, i32::MAX);
+        let mut max = Vector3::new(i32::MIN, i32::MIN, i32::MIN);
+        for instance in &self.instances {
+            for voxel in &self.models[instance.model].voxels {
+                let p = placed(instance, voxel);
+                min = Vector3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+                max = Vector3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+            }
+        }
+
+        if min.x > max.x {
+            return Ok(VoxelGrid::new(Vector3::new(0, 0, 0), Vector3::new(0.0, 0.0, 0.0)));
+        }
+
+        let extent = Vector3::new(
+            (max.x as i64 - min.x as i64 + 1) as u64,
+            (max.y as i64 - min.y as i64 + 1) as u64,
+            (max.z as i64 - min.z as i64 + 1) as u64
+        );
+        if extent.x.saturating_mul(extent.y).saturating_mul(extent.z) > MAX_SCENE_CELLS {
+            return Err(VoxError::TooLarge { size: extent });
+        }
+
+        let size = Vector3::new(extent.x as u32, extent.y as u32, extent.z as u32);
+        let mut grid = VoxelGrid::new(size, Vector3::new(size.x as f32 / 2.0, size.y as f32 / 2.0, size.z as f32 / 2.0));
+
+        for instance in &self.instances {
+            for voxel in &self.models[instance.model].voxels {
+                let p = placed(instance, voxel) - min;
+                let [r, g, b, _] = self.palette[voxel[3] as usize];
+                // KV6 flips z to point down, and x along with it so the model isn't mirrored
+                grid.set(size.x as i32 - 1 - p.x, p.y, size.z as i32 - 1 - p.z, Some(KV6Color { b, g, r, a: 128 }));
+            }
+        }
+
+        Ok(grid)
+    }
+
+    /// The whole scene as one KV6 model, with visibility and normals worked out from its shape
+    pub fn to_kv6(&self) -> VoxResult<KV6Data> {
+        Ok(self.to_grid()?.to_kv6())
+    }
+
+    /// Fills in the model's interior and reduces its colors to a 255 entry palette.
+    /// Models over 256 voxels along any axis are split into several shapes,
+    /// each placed so that they line up again in MagicaVoxel.
+    pub fn from_kv6(data: &KV6Data) -> VoxScene {
+        let grid = VoxelGrid::from(data);
+        let size = grid.size;
+
+        let mut counts = HashMap::new();
+        for voxel in &data.voxels {
+            *counts.entry([voxel.color.r, voxel.color.g, voxel.color.b]).or_insert(0) += 1;
+        }
+        let colors = median_cut(counts, 255);
+
+        let mut palette = default_palette();
+        for (i, &[r, g, b]) in colors.iter().enumerate() {
+            palette[i + 1] = [r, g, b, 0xff];
+        }
+        let mut quantizer = Quantizer::new(colors);
+
+        let mut models = Vec::new();
+        let mut instances = Vec::new();
+
+        for ox in (0..size.x).step_by(MAX_MODEL_SIZE as usize) {
+            for oy in (0..size.y).step_by(MAX_MODEL_SIZE as usize) {
+                for oz in (0..size.z).step_by(MAX_MODEL_SIZE as usize) {
+                    let origin = Vector3::new(ox, oy, oz);
+                    let part_size = Vector3::new(
+                        (size.x - ox).min(MAX_MODEL_SIZE),
+                        (size.y - oy).min(MAX_MODEL_SIZE),
+                        (size.z - oz).min(MAX_MODEL_SIZE)
+                    );
+
+                    let mut voxels = Vec::new();
+                    for x in 0..part_size.x {
+                        for y in 0..part_size.y {
+                            for z in 0..part_size.z {
+                                let v = origin + Vector3::new(x, y, z);
+                                // the inverse of the flip in `to_grid`
+                                let color = grid.color((size.x - 1 - v.x) as i32, v.y as i32, (size.z - 1 - v.z) as i32);
+                                if let Some(color) = color {
+                                    let index = quantizer.index(color) + 1;
+                                    voxels.push([x as u8, y as u8, z as u8, index as u8]);
+                                }
+                            }
+                        }
+                    }
+
+                    if voxels.is_empty() {
+                        continue;
+                    }
+
+                    instances.push(VoxInstance {
+                        model: models.len(),
+                        rotation: VoxRotation::IDENTITY,
+                        translation: Vector3::new(
+                            (ox + part_size.x / 2) as i32 - (size.x / 2) as i32,
+                            (oy + part_size.y / 2) as i32 - (size.y / 2) as i32,
+                            (oz + part_size.z / 2) as i32 - (size.z / 2) as i32
+                        )
+                    });
+                    models.push(VoxModel { size: part_size, voxels });
+                }
+            }
+        }
+
+        VoxScene { models, instances, palette }
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) -> io::Result<()> {
+    out.write_all(id)?;
+    out.write_i32::<LittleEndian>(content.len() as i32)?;
+    out.write_i32::<LittleEndian>(children.len() as i32)?;
+    out.write_all(content)?;
+    out.write_all(children)
+}
+
+fn write_dict(out: &mut Vec<u8>, entries: &[(&str, String)]) -> io::Result<()> {
+    out.write_i32::<LittleEndian>(entries.len() as i32)?;
+    for (key, value) in entries {
+        for string in &[key.as_bytes(), value.as_bytes()] {
+            out.write_i32::<LittleEndian>(string.len() as i32)?;
+            out.write_all(string)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the scene as a root transform holding a group with one transform and shape per instance
+pub fn save_vox<W: Write>(scene: &VoxScene, file: W) -> io::Result<()> {
+    let mut children = Vec::new();
+
+    for model in &scene.models {
+        let mut content = Vec::new();
+        content.write_i32::<LittleEndian>(model.size.x as i32)?;
+        content.write_i32::<LittleEndian>(model.size.y as i32)?;
+        content.write_i32::<LittleEndian>(model.size.z as i32)?;
+        write_chunk(&mut children, b"SIZE", &content, &[])?;
+
+        let mut content = Vec::new();
+        content.write_i32::<LittleEndian>(model.voxels.len() as i32)?;
+        for voxel in &model.voxels {
+            content.write_all(voxel)?;
+        }
+        write_chunk(&mut children, b"XYZI", &content, &[])?;
+    }
+
+    // node 0 is the root transform, node 1 the group, then a transform and shape per instance
+    let mut content = Vec::new();
+    content.write_i32::<LittleEndian>(0)?;
+    write_dict(&mut content, &[])?;
+    content.write_i32::<LittleEndian>(1)?;  // child
+    content.write_i32::<LittleEndian>(-1)?; // reserved
+    content.write_i32::<LittleEndian>(-1)?; // layer
+    content.write_i32::<LittleEndian>(1)?;  // frames
+    write_dict(&mut content, &[])?;
+    write_chunk(&mut children, b"nTRN", &content, &[])?;
+
+    let mut content = Vec::new();
+    content.write_i32::<LittleEndian>(1)?;
+    write_dict(&mut content, &[])?;
+    content.write_i32::<LittleEndian>(scene.instances.len() as i32)?;
+    for i in 0..scene.instances.len() {
+        content.write_i32::<LittleEndian>(2 + i as i32 * 2)?;
+    }
+    write_chunk(&mut children, b"nGRP", &content, &[])?;
+
+    for (i, instance) in scene.instances.iter().enumerate() {
+        let node = 2 + i as i32 * 2;
+        let t = instance.translation;
+
+        let mut content = Vec::new();
+        content.write_i32::<LittleEndian>(node)?;
+        write_dict(&mut content, &[])?;
+        content.write_i32::<LittleEndian>(node + 1)?;
+        content.write_i32::<LittleEndian>(-1)?;
+        content.write_i32::<LittleEndian>(0)?;
+        content.write_i32::<LittleEndian>(1)?;
+        write_dict(&mut content, &[
+            ("_r", instance.rotation.to_byte().to_string()),
+            ("_t", format!("{} {} {}", t.x, t.y, t.z))
+        ])?;
+        write_chunk(&mut children, b"nTRN", &content, &[])?;
+
+        let mut content = Vec::new();
+        content.write_i32::<LittleEndian>(node + 1)?;
+        write_dict(&mut content, &[])?;
+        content.write_i32::<LittleEndian>(1)?;
+        content.write_i32::<LittleEndian>(instance.model as i32)?;
+        write_dict(&mut content, &[])?;
+        write_chunk(&mut children, b"nSHP", &content, &[])?;
+    }
+
+    // entry i of the chunk is palette index i + 1, so index 0 goes last
+    let mut content = Vec::new();
+    for i in 1..=256 {
+        content.write_all(&scene.palette[i % 256])?;
+    }
+    write_chunk(&mut children, b"RGBA", &content, &[])?;
+
+    let mut file = BufWriter::new(file);
+    file.write_all(&VOX_MAGIC)?;
+    file.write_i32::<LittleEndian>(VOX_VERSION)?;
+
+    let mut main = Vec::new();
+    write_chunk(&mut main, b"MAIN", &[], &children)?;
+    file.write_all(&main)?;
+
+    file.flush()
+}
+
+// walks the scene graph down from the root node, which has to be a tree: a node that
+// turns up a second time would place everything under it again, doubling the scene each time
+fn collect_instances(nodes: &HashMap<i32, (usize, Node)>) -> VoxResult<Vec<VoxInstance>> {
+    let mut instances = Vec::new();
+    let mut visited = HashSet::new();
+    // node, where it was referenced from, and the transform it inherits
+    let mut stack = vec![(0, 0, VoxRotation::IDENTITY, Vector3::new(0, 0, 0))];
+
+    while let Some((id, referenced_at, rotation, translation)) = stack.pop() {
+        if !visited.insert(id) {
+            return Err(VoxError::BadNode { offset: referenced_at as u64, node: id });
+        }
+        let (offset, node) = nodes.get(&id)
+            .ok_or(VoxError::BadNode { offset: referenced_at as u64, node: id })?;
+
+        match node {
+            Node::Transform { child, rotation: local_rotation, translation: local_translation } => {
+                let child_rotation = local_rotation.then(&rotation);
+                let child_translation = translation + rotation.apply(*local_translation);
+                stack.push((*child, *offset, child_rotation, child_translation));
+            },
+            Node::Group { children } => {
+                // reversed so children come off the stack in the order they're listed
+                for &child in children.iter().rev() {
+                    stack.push((child, *offset, rotation, translation));
+                }
+            },
+            Node::Shape { models } => {
+                for &model in models {
+                    instances.push(VoxInstance { model, rotation, translation });
+                }
+            }
+        }
+    }
+
+    Ok(instances)
+}
+
+pub fn load_vox(path: &str) -> VoxResult<VoxScene> {
+    VoxScene::from_reader(BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use byteorder::WriteBytesExt;
+
+    fn chunk(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.write_i32::<LittleEndian>(content.len() as i32).unwrap();
+        bytes.write_i32::<LittleEndian>(0).unwrap();
+        bytes.extend_from_slice(content);
+        bytes
+    }
+
+    fn file(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let children = chunks.concat();
+        let mut bytes = b"VOX ".to_vec();
+        bytes.write_i32::<LittleEndian>(150).unwrap();
+        bytes.extend_from_slice(b"MAIN");
+        bytes.write_i32::<LittleEndian>(0).unwrap();
+        bytes.write_i32::<LittleEndian>(children.len() as i32).unwrap();
+        bytes.extend_from_slice(&children);
+        bytes
+    }
+
+    fn ints(values: &[i32]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &value in values {
+            bytes.write_i32::<LittleEndian>(value).unwrap();
+        }
+        bytes
+    }
+
+    // a model with `count` voxels of color 1 along x, then y
+    fn model(count: i32) -> Vec<Vec<u8>> {
+        let mut xyzi = ints(&[count]);
+        for i in 0..count {
+            xyzi.extend_from_slice(&[(i % 256) as u8, (i / 256) as u8, 0, 1]);
+        }
+        vec![chunk(b"SIZE", &ints(&[256, 256, 1])), chunk(b"XYZI", &xyzi)]
+    }
+
+    // no attributes, no animation frames, so no transform either
+    fn transform(node: i32, child: i32) -> Vec<u8> {
+        chunk(b"nTRN", &ints(&[node, 0, child, -1, 0, 0]))
+    }
+
+    fn group(node: i32, children: &[i32]) -> Vec<u8> {
+        chunk(b"nGRP", &[ints(&[node, 0, children.len() as i32]), ints(children)].concat())
+    }
+
+    fn shape(node: i32, models: &[i32]) -> Vec<u8> {
+        let mut content = ints(&[node, 0, models.len() as i32]);
+        for &model in models {
+            content.extend_from_slice(&ints(&[model, 0]));
+        }
+        chunk(b"nSHP", &content)
+    }
+
+    #[test]
+    fn loads_a_model_placed_by_its_transform() {
+        let mut moved = ints(&[0, 0, 1, -1, 0, 1, 1, 2]);
+        moved.extend_from_slice(b"_t");
+        moved.extend_from_slice(&ints(&[5]));
+        moved.extend_from_slice(b"4 0 0");
+
+        let mut chunks = model(3);
+        chunks.push(chunk(b"nTRN", &moved));
+        chunks.push(shape(1, &[0]));
+
+        let scene = VoxScene::from_bytes(&file(&chunks)).unwrap();
+        assert_eq!(scene.instances.len(), 1);
+        assert_eq!(scene.instances[0].translation, Vector3::new(4, 0, 0));
+        assert_eq!(scene.to_kv6().unwrap().voxels.len(), 3);
+    }
+
+    #[test]
+    fn rejects_nodes_that_contain_themselves() {
+        let mut chunks = model(1);
+        chunks.push(transform(0, 0));
+        assert!(matches!(VoxScene::from_bytes(&file(&chunks)), Err(VoxError::BadNode { node: 0, .. })));
+    }
+
+    #[test]
+    fn rejects_shared_subtrees() {
+        // every group lists its child twice, which would double the scene at each level
+        let mut chunks = model(1);
+        for node in 0..24 {
+            chunks.push(group(node, &[node + 1, node + 1]));
+        }
+        chunks.push(shape(24, &[0]));
+        assert!(matches!(VoxScene::from_bytes(&file(&chunks)), Err(VoxError::BadNode { .. })));
+    }
+
+    #[test]
+    fn walks_deeply_nested_transforms() {
+        let mut chunks = model(1);
+        for node in 0..100_000 {
+            chunks.push(transform(node, node + 1));
+        }
+        chunks.push(shape(100_000, &[0]));
+        assert_eq!(VoxScene::from_bytes(&file(&chunks)).unwrap().instances.len(), 1);
+    }
+
+    #[test]
+    fn rejects_shapes_of_missing_models() {
+        let mut chunks = model(1);
+        chunks.push(shape(0, &[3]));
+        assert!(matches!(VoxScene::from_bytes(&file(&chunks)), Err(VoxError::BadModel { model: 3 })));
+    }
+
+    #[test]
+    fn caps_what_a_scene_places() {
+        let mut chunks = model(1);
+        chunks.push(shape(0, &vec![0; MAX_INSTANCES + 1]));
+        let scene = VoxScene::from_bytes(&file(&chunks)).unwrap();
+        assert!(matches!(scene.to_grid(), Err(VoxError::TooManyInstances { .. })));
+
+        let mut chunks = model(300);
+        chunks.push(shape(0, &vec![0; 60_000]));
+        let scene = VoxScene::from_bytes(&file(&chunks)).unwrap();
+        assert!(matches!(scene.to_grid(), Err(VoxError::TooManyVoxels { voxels: 18_000_000 })));
+    }
+    const MODELS: [(&str, &[u8]); 3] = [
+        ("caco", include_bytes!("../../kv6/caco.kv6")),
+        ("desklamp", include_bytes!("../../kv6/desklamp.kv6")),
+        ("light", include_bytes!("../../kv6/light.kv6")),
+    ];
+
+    #[test]
+    fn saved_models_load_back_the_same() {
+        for &(name, bytes) in MODELS.iter() {
+            let data = KV6Data::from_bytes(bytes).unwrap();
+            let scene = VoxScene::from_kv6(&data);
+            let mut saved = Vec::new();
+            save_vox(&scene, &mut saved).unwrap();
+            let loaded = VoxScene::from_bytes(&saved).unwrap();
+
+            assert_eq!(loaded.models.len(), scene.models.len(), "{}", name);
+            for (loaded, model) in loaded.models.iter().zip(&scene.models) {
+                assert_eq!(loaded.size, model.size, "{}", name);
+                assert!(loaded.voxels == model.voxels, "{} voxels changed", name);
+            }
+            assert_eq!(loaded.instances.len(), scene.instances.len(), "{}", name);
+            for (loaded, instance) in loaded.instances.iter().zip(&scene.instances) {
+                assert_eq!((loaded.model, loaded.rotation, loaded.translation), (instance.model, instance.rotation, instance.translation), "{}", name);
+            }
+            assert!(loaded.palette[1..] == scene.palette[1..], "{} palette changed", name);
+
+            // the surface comes back where it was, colors aside
+            let converted = loaded.to_kv6().unwrap();
+            assert_eq!(converted.voxels.len(), data.voxels.len(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use crate::{KV6Color, KV6Voxel, KV6Data, Palette};
+use crate::{LEFT_VISIBLE, RIGHT_VISIBLE, BACK_VISIBLE, FRONT_VISIBLE, TOP_VISIBLE, BOTTOM_VISIBLE};
+use crate::kv6mesh::legacy;
+use crate::normals::{estimate_normal_index, DEFAULT_NORMAL_RADIUS};
+
+use std::collections::VecDeque;
+
+use cgmath::Vector3;
+
+// neighbour offsets in KV6 space along with the visibility bit for that side
+pub const NEIGHBOURS: [(i32, i32, i32, u8); 6] = [
+    (-1,  0,  0, LEFT_VISIBLE),
+    ( 1,  0,  0, RIGHT_VISIBLE),
+    ( 0, -1,  0, BACK_VISIBLE),
+    ( 0,  1,  0, FRONT_VISIBLE),
+    ( 0,  0, -1, TOP_VISIBLE),
+    ( 0,  0,  1, BOTTOM_VISIBLE),
+];
+
+/// Anything that can tell solid cells from air, in KV6 space
+pub trait Occupancy {
+    fn is_solid(&self, x: i32, y: i32, z: i32) -> bool;
+
+    /// Visibility bits for a cell, from which of its neighbours are air
+    fn visibility(&self, x: i32, y: i32, z: i32) -> u8 {
+        NEIGHBOURS.iter()
+            .filter(|&&(dx, dy, dz, _)| !self.is_solid(x + dx, y + dy, z + dz))
+            .fold(0, |visibility, &(_, _, _, bit)| visibility | bit)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Cell {
+    color: KV6Color,
+    // kept from the source model so unedited voxels convert back unchanged
+    normal_index: Option<u8>
+}
+
+/// Dense 3D array of voxels in KV6 space, every cell is either air or a solid color.
+/// Unlike `KV6Data` the interior of the model is filled in, which makes editing simple.
+pub struct VoxelGrid {
+    pub size: Vector3<u32>,
+    pub pivot: Vector3<f32>,
+    pub palette: Option<Palette>,
+    cells: Vec<Option<Cell>>
+}
+
+impl VoxelGrid {
+    pub fn new(size: Vector3<u32>, pivot: Vector3<f32>) -> VoxelGrid {
+        VoxelGrid {
+            size,
+            pivot,
+            palette: None,
+            cells: vec![None; size.x as usize * size.y as usize * size.z as usize]
+        }
+    }
+
+    fn index(&self, x: i32, y: i32, z: i32) -> Option<usize> {
+        if x < 0 || y < 0 || z < 0 || x as u32 >= self.size.x || y as u32 >= self.size.y || z as u32 >= self.size.z {
+            return None;
+        }
+
+        Some((x as usize * self.size.y as usize + y as usize) * self.size.z as usize + z as usize)
+    }
+
+    pub fn color(&self, x: i32, y: i32, z: i32) -> Option<KV6Color> {
+        self.index(x, y, z).and_then(|i| self.cells[i]).map(|cell| cell.color)
+    }
+
+    /// Sets a cell to the given color, or to air with `None`.
+    /// Panics if the position is outside of the grid.
+    pub fn set(&mut self, x: i32, y: i32, z: i32, color: Option<KV6Color>) {
+        let i = self.index(x, y, z).expect("position outside of the voxel grid");
+        self.cells[i] = color.map(|color| Cell { color, normal_index: None });
+    }
+
+    pub fn normal_index(&self, x: i32, y: i32, z: i32) -> Option<u8> {
+        self.index(x, y, z).and_then(|i| self.cells[i]).and_then(|cell| cell.normal_index)
+    }
+
+    // flood fills the air connected to the outside of the grid or to a face `data` marks visible,
+    // which keeps cavities sealed inside the model open
+    fn open_air(&self, data: &KV6Data) -> Vec<bool> {
+        let mut outside = vec![false; self.cells.len()];
+        let mut queue = VecDeque::new();
+
+        for (x, y, z, voxel) in data.iter_voxels() {
+            let (x, y, z) = (x as i32, y as i32, z as i32);
+            for &(dx, dy, dz, bit) in NEIGHBOURS.iter() {
+                if voxel.visibility & bit == 0 {
+                    continue;
+                }
+                if let Some(i) = self.index(x + dx, y + dy, z + dz) {
+                    if !outside[i] && self.cells[i].is_none() {
+                        outside[i] = true;
+                        queue.push_back((x + dx, y + dy, z + dz));
+                    }
+                }
+            }
+        }
+
+        let (sx, sy, sz) = (self.size.x as i32, self.size.y as i32, self.size.z as i32);
+        for x in 0..sx {
+            for y in 0..sy {
+                for z in 0..sz {
+                    let on_border = x == 0 || y == 0 || z == 0 || x == sx - 1 || y == sy - 1 || z == sz - 1;
+                    let i = self.index(x, y, z).unwrap();
+                    if on_border && !outside[i] && self.cells[i].is_none() {
+                        outside[i] = true;
+                        queue.push_back((x, y, z));
+                    }
+                }
+            }
+        }
+
+        while let Some((x, y, z)) = queue.pop_front() {
+            for &(dx, dy, dz, _) in NEIGHBOURS.iter() {
+                if let Some(i) = self.index(x + dx, y + dy, z + dz) {
+                    if !outside[i] && self.cells[i].is_none() {
+                        outside[i] = true;
+                        queue.push_back((x + dx, y + dy, z + dz));
+                    }
+                }
+            }
+        }
+
+        outside
+    }
+
+    /// Halves the resolution. A cell of the result is solid if any of the
+    /// eight cells it covers are, and takes the average of their colors.
+    pub fn downsample(&self) -> VoxelGrid {
+        let size = Vector3::new(self.size.x.div_ceil(2), self.size.y.div_ceil(2), self.size.z.div_ceil(2));
+        let mut grid = VoxelGrid::new(size, self.pivot / 2.0);
+        grid.palette = self.palette;
+
+        for x in 0..size.x as i32 {
+            for y in 0..size.y as i32 {
+                for z in 0..size.z as i32 {
+                    let mut sum = [0u32; 4];
+                    let mut count = 0;
+                    for dx in 0..2 {
+                        for dy in 0..2 {
+                            for dz in 0..2 {
+                                if let Some(color) = self.color(x * 2 + dx, y * 2 + dy, z * 2 + dz) {
+                                    sum[0] += color.b as u32;
+                                    sum[1] += color.g as u32;
+                                    sum[2] += color.r as u32;
+                                    sum[3] += color.a as u32;
+                                    count += 1;
+                                }
+                            }
+                        }
+                    }
+
+                    if count == 0 {
+                        continue;
+                    }
+
+                    grid.set(x, y, z, Some(KV6Color {
+                        b: (sum[0] / count) as u8,
+                        g: (sum[1] / count) as u8,
+                        r: (sum[2] / count) as u8,
+                        a: (sum[3] / count) as u8
+                    }));
+                }
+            }
+        }
+
+        grid
+    }
+
+    /// Converts back into sparse columns, keeping only solid cells that touch air.
+    /// Cells that weren't loaded from a KV6 get an estimated normal, see `normals::estimate_normal`.
+    pub fn to_kv6(&self) -> KV6Data {
+        let normal_table = legacy::create_normal_table();
+
+        let mut voxels = Vec::new();
+        let mut xy_entries = Vec::with_capacity(self.size.x as usize * self.size.y as usize);
+
+        for x in 0..self.size.x as i32 {
+            for y in 0..self.size.y as i32 {
+                let mut count = 0;
+
+                for z in 0..self.size.z as i32 {
+                    let cell = match self.index(x, y, z).and_then(|i| self.cells[i]) {
+                        Some(cell) => cell,
+                        None => continue
+                    };
+
+                    let visibility = self.visibility(x, y, z);
+                    if visibility == 0 {
+                        continue;
+                    }
+
+                    voxels.push(KV6Voxel {
+                        color: cell.color,
+                        z: z as u16,
+                        visibility,
+                        normal_index: cell.normal_index.unwrap_or_else(|| {
+                            estimate_normal_index(self, &normal_table, x, y, z, DEFAULT_NORMAL_RADIUS)
+                        })
+                    });
+                    count += 1;
+                }
+
+                xy_entries.push(count);
+            }
+        }
+
+        KV6Data::new(self.size, self.pivot, voxels, xy_entries, self.palette)
+    }
+}
+
+impl Occupancy for VoxelGrid {
+    /// Anything outside of the grid counts as air
+    fn is_solid(&self, x: i32, y: i32, z: i32) -> bool {
+        self.index(x, y, z).is_some_and(|i| self.cells[i].is_some())
+    }
+}
+
+impl Occupancy for KV6Data {
+    /// Only the stored voxels, buried ones count as air. That's all there can be
+    /// next to an air cell, which is where meshing looks.
+    fn is_solid(&self, x: i32, y: i32, z: i32) -> bool {
+        if x < 0 || y < 0 || z < 0 || z > u16::MAX as i32 {
+            return false;
+        }
+
+        self.voxel_at(x as u32, y as u32, z as u16).is_some()
+    }
+}
+
+impl From<&KV6Data> for VoxelGrid {
+    /// Places every surface voxel, then fills everything that can't be reached from outside
+    /// the model or from a face it marks visible. Interior cells take the color of the voxel above them.
+    fn from(data: &KV6Data) -> VoxelGrid {
+        let mut grid = VoxelGrid::new(data.size, data.pivot);
+        grid.palette = data.palette;
+
+        for (x, y, z, voxel) in data.iter_voxels() {
+            let i = grid.index(x as i32, y as i32, z as i32).unwrap();
+            grid.cells[i] = Some(Cell { color: voxel.color, normal_index: Some(voxel.normal_index) });
+        }
+
+        let outside = grid.open_air(data);
+
+        for x in 0..data.size.x as i32 {
+            for y in 0..data.size.y as i32 {
+                let mut above = data.column(x as u32, y as u32).first().map(|voxel| voxel.color);
+
+                for z in 0..data.size.z as i32 {
+                    let i = grid.index(x, y, z).unwrap();
+                    match grid.cells[i] {
+                        Some(cell) => above = Some(cell.color),
+                        None => if !outside[i] {
+                            grid.cells[i] = above.map(|color| Cell { color, normal_index: None });
+                        }
+                    }
+                }
+            }
+        }
+
+        grid
+    }
+}
+
+impl KV6Data {
+    /// Visibility bits worked out from which neighbours are actually occupied,
+    /// one entry per voxel in the same order as `voxels`. Occupied means stored, or
+    /// buried where neither the outside nor any visible face can reach, see `VoxelGrid::from`.
+    pub fn computed_visibility(&self) -> Vec<u8> {
+        let grid = VoxelGrid::from(self);
+        self.iter_voxels()
+            .map(|(x, y, z, _)| grid.visibility(x as i32, y as i32, z as i32))
+            .collect()
+    }
+
+    /// Replaces the stored visibility bits with `computed_visibility`,
+    /// returns how many voxels had wrong bits
+    pub fn recompute_visibility(&mut self) -> usize {
+        let computed = self.computed_visibility();
+        let mut changed = 0;
+        for (voxel, visibility) in self.voxels.iter_mut().zip(computed) {
+            if voxel.visibility != visibility {
+                voxel.visibility = visibility;
+                changed += 1;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODELS: [(&str, &[u8]); 3] = [
+        ("caco", include_bytes!("../../kv6/caco.kv6")),
+        ("desklamp", include_bytes!("../../kv6/desklamp.kv6")),
+        ("light", include_bytes!("../../kv6/light.kv6")),
+    ];
+
+    // an 8x8x8 box with 2 thick walls around a sealed 4x4x4 cavity,
+    // 296 voxels on the outside and 96 facing the cavity
+    fn hollow_box() -> KV6Data {
+        let color = KV6Color { b: 40, g: 80, r: 120, a: 128 };
+        let mut grid = VoxelGrid::new(Vector3::new(8, 8, 8), Vector3::new(4.0, 4.0, 4.0));
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    let inside = [x, y, z].iter().all(|&n| (2..6).contains(&n));
+                    if !inside {
+                        grid.set(x, y, z, Some(color));
+                    }
+                }
+            }
+        }
+        grid.to_kv6()
+    }
+
+    fn voxels(data: &KV6Data) -> Vec<(u32, u32, u16, [u8; 4], u8, u8)> {
+        data.iter_voxels()
+            .map(|(x, y, z, voxel)| (x, y, z, [voxel.color.b, voxel.color.g, voxel.color.r, voxel.color.a], voxel.visibility, voxel.normal_index))
+            .collect()
+    }
+
+    #[test]
+    fn hollow_box_keeps_its_cavity() {
+        assert_eq!(hollow_box().voxels.len(), 392);
+    }
+
+    #[test]
+    fn converts_to_grid_and_back_without_loss() {
+        let mut models: Vec<(&str, KV6Data)> = MODELS.iter()
+            .map(|&(name, bytes)| (name, KV6Data::from_bytes(bytes).unwrap()))
+            .collect();
+        models.push(("hollow box", hollow_box()));
+
+        for (name, data) in &models {
+            let converted = VoxelGrid::from(data).to_kv6();
+            assert!(voxels(&converted) == voxels(data), "{} changed going through a VoxelGrid", name);
+            assert_eq!(converted.xy_entries, data.xy_entries, "{}", name);
+        }
+    }
+
+    #[test]
+    fn recomputing_correct_visibility_changes_nothing() {
+        let mut data = hollow_box();
+        assert_eq!(data.recompute_visibility(), 0);
+    }
+
+    #[test]
+    fn recomputing_visibility_restores_cleared_outside_faces() {
+        let mut data = hollow_box();
+        let stored = data.voxels[0].visibility;
+        data.voxels[0].visibility = 0;
+        assert_eq!(data.recompute_visibility(), 1);
+        assert_eq!(data.voxels[0].visibility, stored);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::kv6data::read_remaining;
+use crate::{KV6Color, KV6Voxel, KV6Data, Occupancy, DEFAULT_NORMAL_RADIUS};
+use crate::kv6mesh::legacy;
+use crate::normals::estimate_normal_index;
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+
+use cgmath::Vector3;
+
+/// Ace of Spades maps are always this big, the format has no header to say otherwise
+pub const VXL_SIZE: Vector3<u32> = Vector3::new(512, 512, 64);
+/// Columns along each side of the pieces a map is meshed in
+pub const VXL_CHUNK_SIZE: u32 = 32;
+
+// every span starts with its length, the top colors' start and end, and where the air above it starts
+const SPAN_HEADER_SIZE: usize = 4;
+
+/// A map stored the way VXL does, as solid columns of which only the colored cells can be seen.
+/// Map coordinates are laid out like KV6 space, with z going down and 63 being the water.
+pub struct VXLMap {
+    pub size: Vector3<u32>,
+    // one bit per z for every column, set where it's solid
+    solid: Vec<u64>,
+    // colored cells of every column from top to bottom, the rest of the solid cells are buried
+    colors: Vec<(u8, KV6Color)>,
+    column_starts: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub enum VXLError {
+    Io(io::Error),
+    Truncated { offset: u64 },
+    BadSpan { offset: u64, x: u32, y: u32 },
+}
+
+impl fmt::Display for VXLError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VXLError::Io(err) =>
+                write!(f, "{}", err),
+            VXLError::Truncated { offset } =>
+                write!(f, "VXL file ends early at byte {}", offset),
+            VXLError::BadSpan { offset, x, y } =>
+                write!(f, "span at byte {} in column {},{} doesn't fit the map", offset, x, y),
+        }
+    }
+}
+
+impl std::error::Error for VXLError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VXLError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for VXLError {
+    fn from(err: io::Error) -> VXLError {
+        VXLError::Io(err)
+    }
+}
+
+pub type VXLResult<T> = std::result::Result<T, VXLError>;
+
+// bits for the cells from `start` up to but not including `end`
+fn span_bits(start: u32, end: u32) -> u64 {
+    let below_end = if end >= 64 { !0 } else { (1 << end) - 1 };
+    below_end & !((1 << start) - 1)
+}
+
+fn read_colors(bytes: &[u8], offset: usize, count: usize) -> VXLResult<impl Iterator<Item = KV6Color> + '_> {
+    let colors = bytes.get(offset..offset + count * 4)
+        .ok_or(VXLError::Truncated { offset: bytes.len() as u64 })?;
+
+    Ok(colors.chunks_exact(4).map(|color| KV6Color { b: color[0], g: color[1], r: color[2], a: color[3] }))
+}
+
+impl VXLMap {
+    pub fn from_reader<R: Read + Seek>(mut file: R) -> VXLResult<VXLMap> {
+        let bytes = read_remaining(&mut file)?;
+
+        VXLMap::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> VXLResult<VXLMap> {
+        let size = VXL_SIZE;
+        let columns = size.x as usize * size.y as usize;
+
+        let mut solid = Vec::with_capacity(columns);
+        let mut colors = Vec::new();
+        let mut column_starts = Vec::with_capacity(columns + 1);
+
+        let mut offset = 0;
+
+        // columns are stored row by row, x changing fastest
+        for y in 0..size.y {
+            for x in 0..size.x {
+                column_starts.push(colors.len());
+
+                let mut mask = 0;
+                // the first span's air always starts at the top
+                let mut air_start = 0;
+
+                loop {
+                    let header = bytes.get(offset..offset + SPAN_HEADER_SIZE)
+                        .ok_or(VXLError::Truncated { offset: bytes.len() as u64 })?;
+                    let (length, top_start, top_end) = (header[0] as usize, header[1] as u32, header[2] as u32);
+                    let bad_span = VXLError::BadSpan { offset: offset as u64, x, y };
+
+                    // a span without top colors has its end one above its start
+                    if top_start < air_start || top_start >= size.z || top_end >= size.z || top_end + 1 < top_start {
+                        return Err(bad_span);
+                    }
+
+                    let top_count = (top_end + 1 - top_start) as usize;
+                    let top_colors = read_colors(bytes, offset + SPAN_HEADER_SIZE, top_count)?;
+                    colors.extend(top_colors.enumerate().map(|(i, color)| ((top_start + i as u32) as u8, color)));
+
+                    // the last span is solid all the way down
+                    if length == 0 {
+                        mask |= span_bits(top_start, size.z);
+                        offset += SPAN_HEADER_SIZE + top_count * 4;
+                        break;
+                    }
+
+                    if length < top_count + 1 {
+                        return Err(bad_span);
+                    }
+
+                    // the bottom colors end where the next span's air starts
+                    let bottom_count = length - 1 - top_count;
+                    let bottom_offset = offset + SPAN_HEADER_SIZE + top_count * 4;
+                    offset += length * 4;
+
+                    let next_air_start = *bytes.get(offset + 3)
+                        .ok_or(VXLError::Truncated { offset: bytes.len() as u64 })? as u32;
+                    if next_air_start < top_end + 1 + bottom_count as u32 {
+                        return Err(bad_span);
+                    }
+
+                    let bottom_start = next_air_start - bottom_count as u32;
+                    let bottom_colors = read_colors(bytes, bottom_offset, bottom_count)?;
+                    colors.extend(bottom_colors.enumerate().map(|(i, color)| ((bottom_start + i as u32) as u8, color)));
+
+                    mask |= span_bits(top_start, next_air_start);
+                    air_start = next_air_start;
+                }
+
+                solid.push(mask);
+            }
+        }
+
+        column_starts.push(colors.len());
+
+        Ok(VXLMap { size, solid, colors, column_starts })
+    }
+
+    fn column_index(&self, x: u32, y: u32) -> usize {
+        y as usize * self.size.x as usize + x as usize
+    }
+
+    /// Colored cells of a column from top to bottom, as z and color
+    pub fn column(&self, x: u32, y: u32) -> &[(u8, KV6Color)] {
+        let i = self.column_index(x, y);
+        &self.colors[self.column_starts[i]..self.column_starts[i + 1]]
+    }
+
+    /// The visible cells of a block of columns as a model, with visibility and normals worked out
+    /// from the map around it. Its pivot sits at the map's origin, so every chunk lines up.
+    pub fn chunk_to_kv6(&self, x: u32, y: u32, size_x: u32, size_y: u32) -> KV6Data {
+        let normal_table = legacy::create_normal_table();
+
+        let mut voxels = Vec::new();
+        let mut xy_entries = Vec::with_capacity(size_x as usize * size_y as usize);
+
+        for cx in x..x + size_x {
+            for cy in y..y + size_y {
+                let mut count = 0;
+
+                for &(z, color) in self.column(cx, cy) {
+                    let (vx, vy, vz) = (cx as i32, cy as i32, z as i32);
+
+                    let visibility = self.visibility(vx, vy, vz);
+                    if visibility == 0 {
+                        continue;
+                    }
+
+                    voxels.push(KV6Voxel {
+                        color,
+                        z: z as u16,
+                        visibility,
+                        normal_index: estimate_normal_index(self, &normal_table, vx, vy, vz, DEFAULT_NORMAL_RADIUS)
+                    });
+                    count += 1;
+                }
+
+                xy_entries.push(count);
+            }
+        }
+
+        let size = Vector3::new(size_x, size_y, self.size.z);
+        let pivot = Vector3::new(-(x as f32), -(y as f32), 0.0);
+        KV6Data::new(size, pivot, voxels, xy_entries, None)
+    }
+
+    /// The whole map in chunks of `VXL_CHUNK_SIZE` columns, with the column each starts at, see `chunk_to_kv6`
+    pub fn chunks(&self) -> impl Iterator<Item = (u32, u32, KV6Data)> + '_ {
+        (0..self.size.x).step_by(VXL_CHUNK_SIZE as usize).flat_map(move |x| {
+            (0..self.size.y).step_by(VXL_CHUNK_SIZE as usize).map(move |y| {
+                let size_x = (self.size.x - x).min(VXL_CHUNK_SIZE);
+                let size_y = (self.size.y - y).min(VXL_CHUNK_SIZE);
+                (x, y, self.chunk_to_kv6(x, y, size_x, size_y))
+            })
+        })
+    }
+
+    /// The map as seen from the chunk starting at column `x`, `y`, so meshing it
+    /// can look past the chunk's edges.
+    pub fn chunk_occupancy(&self, x: u32, y: u32) -> VXLChunkOccupancy<'_> {
+        VXLChunkOccupancy { map: self, x: x as i32, y: y as i32 }
+    }
+}
+
+/// See `VXLMap::chunk_occupancy`
+pub struct VXLChunkOccupancy<'a> {
+    map: &'a VXLMap,
+    x: i32,
+    y: i32,
+}
+
+impl Occupancy for VXLChunkOccupancy<'_> {
+    fn is_solid(&self, x: i32, y: i32, z: i32) -> bool {
+        self.map.is_solid(self.x + x, self.y + y, z)
+    }
+}
+
+impl Occupancy for VXLMap {
+    /// The map is closed off below and around its edges, only the sky above it is open
+    fn is_solid(&self, x: i32, y: i32, z: i32) -> bool {
+        if z < 0 {
+            return false;
+        }
+        if x < 0 || y < 0 || x as u32 >= self.size.x || y as u32 >= self.size.y || z as u32 >= self.size.z {
+            return true;
+        }
+
+        self.solid[self.column_index(x as u32, y as u32)] >> z & 1 != 0
+    }
+}
+
+pub fn load_vxl(path: &str) -> VXLResult<VXLMap> {
+    VXLMap::from_reader(BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TOP_VISIBLE, BOTTOM_VISIBLE};
+
+    fn color(i: u8) -> [u8; 4] {
+        [i, i, i, 128]
+    }
+
+    // every column is only the water, except the first which has an overhang above the ground
+    fn fixture() -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        // cells 10 to 14 with two colors on top and one below, then air down to the ground at 20
+        bytes.extend([4, 10, 11, 0]);
+        bytes.extend(color(1));
+        bytes.extend(color(2));
+        bytes.extend(color(3));
+        bytes.extend([0, 20, 20, 15]);
+        bytes.extend(color(4));
+
+        for _ in 1..VXL_SIZE.x * VXL_SIZE.y {
+            bytes.extend([0, 63, 63, 0]);
+            bytes.extend(color(5));
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn loads_columns_with_several_spans() {
+        let map = VXLMap::from_bytes(&fixture()).unwrap();
+
+        let column: Vec<_> = map.column(0, 0).iter().map(|&(z, color)| (z, color.r)).collect();
+        assert_eq!(column, vec![(10, 1), (11, 2), (14, 3), (20, 4)]);
+        assert_eq!(map.column(1, 0).len(), 1);
+
+        let solid: Vec<_> = (0..64).filter(|&z| map.is_solid(0, 0, z)).collect();
+        let expected: Vec<_> = (10..15).chain(20..64).collect();
+        assert_eq!(solid, expected);
+
+        // the bottom of the overhang faces the air under it, the cells above it only the sides
+        assert_eq!(map.visibility(0, 0, 14) & BOTTOM_VISIBLE, BOTTOM_VISIBLE);
+        assert_eq!(map.visibility(0, 0, 12) & (TOP_VISIBLE | BOTTOM_VISIBLE), 0);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let mut bytes = fixture();
+        bytes.pop();
+        assert!(matches!(VXLMap::from_bytes(&bytes), Err(VXLError::Truncated { .. })));
+
+        // the ground can't start inside the overhang
+        let mut bytes = fixture();
+        bytes[19] = 12;
+        assert!(matches!(VXLMap::from_bytes(&bytes), Err(VXLError::BadSpan { offset: 0, x: 0, y: 0 })));
+    }
+}
@@ -11,4 +11,10 @@ pub const KEY_UP: VirtualKeyCode = VirtualKeyCode::Space;
 pub const KEY_DOWN: VirtualKeyCode = VirtualKeyCode::LControl;
 pub const KEY_BOOST: VirtualKeyCode = VirtualKeyCode::LShift;
 pub const KEY_MOVE_LIGHT: VirtualKeyCode = VirtualKeyCode::L;
-pub const KEY_SHOW_LIGHT: VirtualKeyCode = VirtualKeyCode::K;
\ No newline at end of file
+pub const KEY_SHOW_LIGHT: VirtualKeyCode = VirtualKeyCode::K;
+pub const KEY_AMBIENT_OCCLUSION: VirtualKeyCode = VirtualKeyCode::B;
+pub const KEY_COMPUTED_VISIBILITY: VirtualKeyCode = VirtualKeyCode::V;
+pub const KEY_COMPUTED_NORMALS: VirtualKeyCode = VirtualKeyCode::N;
+pub const KEY_GREEDY_MESHING: VirtualKeyCode = VirtualKeyCode::G;
+pub const KEY_SMOOTH_MESHING: VirtualKeyCode = VirtualKeyCode::M;
+pub const KEY_EXPORT_OBJ: VirtualKeyCode = VirtualKeyCode::O;
\ No newline at end of file
@@ -1,5 +0,0 @@
-pub mod kv6data;
-pub mod kv6mesh;
-
-pub use kv6data::*;
-pub use kv6mesh::*;
\ No newline at end of file
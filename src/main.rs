@@ -1,5 +1,8 @@
 #![feature(div_duration)]
 use std::time::{Duration, Instant};
+use std::path::Path;
+use std::fs::File;
+use std::borrow::Cow;
 
 use clap::{Arg, ArgMatches, App};
 
@@ -18,7 +21,6 @@ mod eventutil;
 mod controls;
 mod camera;
 mod shaders;
-mod kv6;
 
 const WINDOW_WIDTH: u32 = 640;
 const WINDOW_HEIGHT: u32 = 480;
@@ -34,10 +36,28 @@ struct Viewer {
     light_kv6: kv6::KV6Mesh,
     show_light: bool,
     
-    user_kv6: kv6::KV6Mesh,
+    // no model when viewing a map
+    user_path: String,
+    user_data: Option<kv6::KV6Data>,
+    user_kv6: Option<kv6::KV6Mesh>,
+    map_data: Option<kv6::VXLMap>,
+    map: Option<kv6::VXLMesh>,
+    // the map's chunks, kept to mesh them again without redoing their normals
+    map_chunks: Vec<(u32, u32, kv6::KV6Data)>,
+    placed_models: Vec<(kv6::KV6Mesh, Vector3<f32>)>,
+    mesh_settings: MeshSettings,
     aos_team_color: Vector3<f32>
 }
 
+// how the user's model is turned into a mesh, changed with the toggle keys
+#[derive(Debug, Copy, Clone)]
+struct MeshSettings {
+    computed_visibility: bool,
+    computed_normals: bool,
+    meshing: kv6::Meshing,
+    ao: bool
+}
+
 fn set_capture(display: &Display, capture: bool) {
     display.gl_window().window().set_cursor_grab(capture).unwrap();
     display.gl_window().window().set_cursor_visible(!capture);
@@ -64,8 +84,121 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .help("Replace voxels colored 0,0,0 with this color.")
             .number_of_values(3)
             .required(false))
+        .arg(Arg::with_name("mip")
+            .long("mip")
+            .help("Mip level to show for KVX models, 0 is the most detailed.")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("convert")
+            .long("convert")
+            .help("Write the model to this file instead of viewing it, the format is picked from the extension (kv6, kvx, vox, qb).")
+            .takes_value(true))
+        .arg(Arg::with_name("export-obj")
+            .long("export-obj")
+            .help("Write the mesh the viewer would show to this OBJ file instead of viewing it, along with an MTL and TGA palette texture.")
+            .takes_value(true))
+        .arg(Arg::with_name("export-ply")
+            .long("export-ply")
+            .help("Write a binary PLY instead of viewing the model, with colors and the model's own smooth normals.")
+            .takes_value(true))
+        .arg(Arg::with_name("ply-mode")
+            .long("ply-mode")
+            .help("Write the mesh the viewer would show to the PLY, or a point at the centre of every voxel.")
+            .possible_values(&["mesh", "points"])
+            .default_value("mesh"))
+        .arg(Arg::with_name("export-glb")
+            .long("export-glb")
+            .help("Write the mesh the viewer would show to this self-contained glTF binary instead of viewing it.")
+            .takes_value(true))
+        .arg(Arg::with_name("glb-normals")
+            .long("glb-normals")
+            .help("Give the GLB the model's own smooth normals, or flat ones straight out of each face.")
+            .possible_values(&["smooth", "face"])
+            .default_value("smooth"))
+        .arg(Arg::with_name("export-stl")
+            .long("export-stl")
+            .help("Write a closed, manifold shell of the model to this STL file for 3D printing instead of viewing it.")
+            .takes_value(true))
+        .arg(Arg::with_name("stl-ascii")
+            .long("stl-ascii")
+            .help("Write the STL as text instead of binary."))
+        .arg(Arg::with_name("mm-per-voxel")
+            .long("mm-per-voxel")
+            .help("Size of a voxel in the STL, in millimetres.")
+            .takes_value(true)
+            .default_value("1"))
+        .arg(Arg::with_name("palette")
+            .long("palette")
+            .help("Palette to quantize to when converting to KVX, 768 bytes of 6-bit RGB like the start of Build's PALETTE.DAT. Defaults to the model's own palette, or one made from its colors.")
+            .takes_value(true))
+        .arg(Arg::with_name("computed-visibility")
+            .long("computed-visibility")
+            .help("Mesh using visibility worked out from neighbouring voxels instead of the stored bits."))
+        .arg(Arg::with_name("place")
+            .long("place")
+            .help("Place another model with its pivot at these map coordinates, can be given more than once.")
+            .value_names(&["model", "x", "y", "z"])
+            .number_of_values(4)
+            .multiple(true))
+        .arg(Arg::with_name("meshing")
+            .long("meshing")
+            .help("Mesh with one quad per face, merge faces of the same color (and normal) into larger ones, or smooth the voxels out.")
+            .possible_values(&["faces", "greedy", "greedy-normals", "smooth"])
+            .default_value("faces"))
+        .arg(Arg::with_name("computed-normals")
+            .long("computed-normals")
+            .help("Light the model with normals estimated from its shape instead of the stored ones."))
+        .arg(Arg::with_name("no-ao")
+            .long("no-ao")
+            .help("Start without darkening the corners neighbouring voxels shade."))
         .get_matches();
 
+    if let Some(output) = matches.value_of("convert") {
+        return convert(&matches, output);
+    }
+    if let Some(output) = matches.value_of("export-obj") {
+        let mip = matches.value_of("mip").unwrap().parse::<usize>()?;
+        let data = load_model(matches.value_of("file").unwrap(), mip)?;
+        kv6::export_obj(&build_user_mesh_data(&data, export_settings(&matches)), output)?;
+        return Ok(());
+    }
+    if let Some(output) = matches.value_of("export-ply") {
+        let mip = matches.value_of("mip").unwrap().parse::<usize>()?;
+        let data = load_model(matches.value_of("file").unwrap(), mip)?;
+        let settings = export_settings(&matches);
+        let mesh = match matches.value_of("ply-mode").unwrap() {
+            "points" => kv6::KV6MeshData::voxel_points(&recomputed_data(&data, settings)),
+            _ => build_user_mesh_data(&data, settings)
+        };
+        kv6::save_ply(&mesh, File::create(output)?)?;
+        return Ok(());
+    }
+    if let Some(output) = matches.value_of("export-glb") {
+        let mip = matches.value_of("mip").unwrap().parse::<usize>()?;
+        let data = load_model(matches.value_of("file").unwrap(), mip)?;
+        let normals = match matches.value_of("glb-normals").unwrap() {
+            "face" => kv6::GltfNormals::Face,
+            _ => kv6::GltfNormals::Smooth
+        };
+        let mesh = build_user_mesh_data(&data, export_settings(&matches));
+        kv6::save_glb(&mesh, data.pivot, normals, File::create(output)?)?;
+        return Ok(());
+    }
+    if let Some(output) = matches.value_of("export-stl") {
+        let mip = matches.value_of("mip").unwrap().parse::<usize>()?;
+        let data = load_model(matches.value_of("file").unwrap(), mip)?;
+        let options = kv6::StlOptions {
+            binary: !matches.is_present("stl-ascii"),
+            mm_per_voxel: matches.value_of("mm-per-voxel").unwrap().parse::<f32>()?
+        };
+        let shell = kv6::StlShell::from_data(&data);
+        if shell.filled > 0 {
+            println!("Filled {} voxels where others only touched along an edge or at a corner", shell.filled);
+        }
+        kv6::save_stl(&shell, &options, File::create(output)?)?;
+        return Ok(());
+    }
+
     let event_loop = EventLoop::new();
     let wb = glutin::window::WindowBuilder::new()
         .with_inner_size((WINDOW_WIDTH, WINDOW_HEIGHT).into())
@@ -84,11 +217,83 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn extension(path: &str) -> Option<String> {
+    Path::new(path).extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase())
+}
+
+fn convert(matches: &ArgMatches, output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mip = matches.value_of("mip").unwrap().parse::<usize>()?;
+    // file match guaranteed (required), unwrap
+    let data = load_model(matches.value_of("file").unwrap(), mip)?;
+
+    match extension(output).as_deref() {
+        Some("kv6") => kv6::save_kv6(&data, File::create(output)?)?,
+        Some("kvx") => {
+            let palette = match matches.value_of("palette") {
+                Some(path) => {
+                    let bytes = std::fs::read(path)?;
+                    if bytes.len() < kv6::Palette::BYTE_SIZE {
+                        return Err(format!("{} is too short to hold a palette", path).into());
+                    }
+                    let mut palette_bytes = [0; kv6::Palette::BYTE_SIZE];
+                    palette_bytes.copy_from_slice(&bytes[..kv6::Palette::BYTE_SIZE]);
+                    Some(kv6::Palette::from_bytes(&palette_bytes))
+                },
+                None => None
+            };
+
+            let kvx = kv6::KVXData::from_kv6(&data, palette.as_ref(), kv6::MAX_MIPS);
+            kv6::save_kvx(&kvx, File::create(output)?)?;
+        },
+        Some("vox") => kv6::save_vox(&kv6::VoxScene::from_kv6(&data), File::create(output)?)?,
+        Some("qb") => {
+            let name = Path::new(output).file_stem().and_then(|stem| stem.to_str()).unwrap_or("model");
+            kv6::save_qb(&kv6::QBScene::from_kv6(&data, name), kv6::QBOptions::default(), File::create(output)?)?;
+        },
+        _ => return Err(format!("don't know how to write {}", output).into())
+    }
+
+    Ok(())
+}
+
+fn mesh_settings(matches: &ArgMatches) -> MeshSettings {
+    MeshSettings {
+        computed_visibility: matches.is_present("computed-visibility"),
+        computed_normals: matches.is_present("computed-normals"),
+        meshing: match matches.value_of("meshing").unwrap() {
+            "greedy" => kv6::Meshing::Greedy { match_normals: false },
+            "greedy-normals" => kv6::Meshing::Greedy { match_normals: true },
+            "smooth" => kv6::Meshing::SurfaceNets,
+            _ => kv6::Meshing::Faces
+        },
+        ao: !matches.is_present("no-ao")
+    }
+}
+
+// none of the export formats carry occlusion, it would only split faces the colors let merge
+fn export_settings(matches: &ArgMatches) -> MeshSettings {
+    MeshSettings { ao: false, ..mesh_settings(matches) }
+}
+
 fn init_data(matches: ArgMatches, display: &Display) -> Result<Viewer, Box<dyn std::error::Error>> {
-    let camera = camera::Camera::new(
-        Vector3::new(0.0, 32.0, 0.0),
-        Vector3::new(0.0, -1.0, 0.0).normalize()
-    );
+    // file match guaranteed (required), unwrap
+    let path = matches.value_of("file").unwrap();
+    let is_map = extension(path).as_deref() == Some("vxl");
+
+    let camera = if is_map {
+        // above the middle of the map
+        camera::Camera::new(
+            Vector3::new(-(kv6::VXL_SIZE.x as f32) / 2.0, kv6::VXL_SIZE.y as f32 / 2.0, 16.0),
+            Vector3::new(0.0, 1.0, 0.0)
+        )
+    } else {
+        camera::Camera::new(
+            Vector3::new(0.0, 32.0, 0.0),
+            Vector3::new(0.0, -1.0, 0.0).normalize()
+        )
+    };
 
     let mut aos_team_color = Vector3::new(0.0, 0.0, 0.0);
     if let Some(color_string) = matches.values_of("aos-team-color") {
@@ -104,8 +309,46 @@ fn init_data(matches: ArgMatches, display: &Display) -> Result<Viewer, Box<dyn s
         None)?;
 
     let light_kv6 = kv6::KV6Mesh::from_file("kv6/light.kv6", display)?;
-    // file match guaranteed (required), unwrap
-    let user_kv6 = kv6::KV6Mesh::from_file(matches.value_of("file").unwrap(), display)?;
+    let mip = matches.value_of("mip").unwrap().parse::<usize>()?;
+    let mesh_settings = mesh_settings(&matches);
+
+    let mut placed_models = Vec::new();
+    let mut map_chunks = Vec::new();
+
+    let (user_data, map_data) = match extension(path).as_deref() {
+        Some("vxl") => {
+            // chunks don't store what's buried, so smoothing them would wrap everything underground too
+            if mesh_settings.meshing == kv6::Meshing::SurfaceNets {
+                return Err(format!("{} is a map, which can't be smoothed", path).into());
+            }
+            let map = kv6::load_vxl(path)?;
+            map_chunks = map.chunks().collect::<Vec<_>>();
+            (None, Some(map))
+        },
+        // every matrix is shown as a model of its own
+        Some("qb") => {
+            for (data, position) in kv6::load_qb(path)?.to_kv6_models()? {
+                if !data.voxels.is_empty() {
+                    placed_models.push((kv6::KV6Mesh::from_data(&data, display), position));
+                }
+            }
+            (None, None)
+        },
+        _ => (Some(load_model(path, mip)?), None)
+    };
+    let user_kv6 = user_data.as_ref().map(|data| kv6::KV6Mesh::from_mesh_data(&build_user_mesh_data(data, mesh_settings), display));
+    let map = map_data.as_ref().map(|map| kv6::VXLMesh::from_chunks(map, &map_chunks, mesh_settings.meshing, mesh_settings.ao, display));
+
+    if let Some(values) = matches.values_of("place") {
+        // guaranteed to come in fours by Arg match
+        let values = values.collect::<Vec<_>>();
+        for place in values.chunks(4) {
+            let data = load_model(place[0], mip)?;
+            let position = Vector3::new(place[1].parse::<f32>()?, place[2].parse::<f32>()?, place[3].parse::<f32>()?);
+            // map coordinates to worldspace, the same flip models get
+            placed_models.push((kv6::KV6Mesh::from_data(&data, display), Vector3::new(-position.x, position.y, -position.z)));
+        }
+    }
 
     Ok(Viewer {
         focused: true,
@@ -117,11 +360,91 @@ fn init_data(matches: ArgMatches, display: &Display) -> Result<Viewer, Box<dyn s
         light_kv6,
         show_light: true,
 
+        user_path: path.to_string(),
+        user_data,
         user_kv6,
+        map_data,
+        map,
+        map_chunks,
+        placed_models,
+        mesh_settings,
         aos_team_color
     })
 }
 
+fn load_model(path: &str, mip: usize) -> Result<kv6::KV6Data, Box<dyn std::error::Error>> {
+    match extension(path).as_deref() {
+        Some("kvx") => {
+            let kvx = kv6::load_kvx(path)?;
+            let level = kvx.mips.get(mip)
+                .ok_or_else(|| format!("{} only has {} mip levels", path, kvx.mips.len()))?;
+            Ok(level.to_kv6(&kvx.palette))
+        },
+        Some("vox") => Ok(kv6::load_vox(path)?.to_kv6()?),
+        Some("qb") => Ok(kv6::load_qb(path)?.to_kv6()?),
+        Some("vxl") => Err(format!("{} is a map, which can only be viewed", path).into()),
+        _ => Ok(kv6::load_kv6(path)?)
+    }
+}
+
+// the model with its visibility and normals swapped for computed ones if the settings ask for it
+fn recomputed_data(data: &kv6::KV6Data, settings: MeshSettings) -> Cow<'_, kv6::KV6Data> {
+    if !settings.computed_visibility && !settings.computed_normals {
+        return Cow::Borrowed(data);
+    }
+
+    let mut data = data.clone();
+    if settings.computed_visibility {
+        let changed = data.recompute_visibility();
+        println!("Computed visibility differs from the stored bits on {} of {} voxels", changed, data.voxels.len());
+    }
+    if settings.computed_normals {
+        data.recompute_normals(kv6::DEFAULT_NORMAL_RADIUS);
+    }
+    Cow::Owned(data)
+}
+
+fn build_user_mesh_data(data: &kv6::KV6Data, settings: MeshSettings) -> kv6::KV6MeshData {
+    let data = recomputed_data(data, settings);
+
+    let mesh = kv6::KV6MeshData::from_data_with(&data, settings.meshing, settings.ao);
+    if let kv6::Meshing::Greedy { match_normals } = settings.meshing {
+        let face_triangles = data.voxels.iter().map(|voxel| voxel.visibility.count_ones() as usize * 2).sum::<usize>();
+        println!("Greedy meshing{} gives {} triangles, against {} with a quad per face",
+            if match_normals { " by color and normal" } else { " by color" }, mesh.triangle_count(), face_triangles);
+    }
+    mesh
+}
+
+fn rebuild_user_mesh(viewer: &mut Viewer, display: &Display) {
+    if let Some(user_data) = &viewer.user_data {
+        let mesh = build_user_mesh_data(user_data, viewer.mesh_settings);
+        viewer.user_kv6 = Some(kv6::KV6Mesh::from_mesh_data(&mesh, display));
+    }
+}
+
+fn rebuild_map(viewer: &mut Viewer, display: &Display) {
+    if let Some(map_data) = &viewer.map_data {
+        let settings = viewer.mesh_settings;
+        viewer.map = Some(kv6::VXLMesh::from_chunks(map_data, &viewer.map_chunks, settings.meshing, settings.ao, display));
+    }
+}
+
+// writes the mesh as shown, without occlusion, next to where the viewer was started, named after the model
+fn export_user_obj(viewer: &Viewer) {
+    let mesh = match &viewer.user_data {
+        Some(data) => build_user_mesh_data(data, MeshSettings { ao: false, ..viewer.mesh_settings }),
+        None => return
+    };
+
+    let stem = Path::new(&viewer.user_path).file_stem().and_then(|stem| stem.to_str()).unwrap_or("model");
+    let output = format!("{}.obj", stem);
+    match kv6::export_obj(&mesh, &output) {
+        Ok(()) => println!("Exported {}", output),
+        Err(err) => println!("Couldn't export {}: {}", output, err)
+    }
+}
+
 fn run_loop(mut viewer: Viewer, event_loop: EventLoop<()>, display: Display) {
     let ms_per_update = Duration::new(1, 0).div_f64(60.0); // can't make const Durations in rust?
 
@@ -177,6 +500,37 @@ fn handle_event(viewer: &mut Viewer, event: &Event<()>, display: &Display, actio
                     Some(controls::KEY_EXIT) => if pressed { *action = eventutil::LoopAction::Stop; },
                     Some(controls::KEY_MOVE_LIGHT) => if pressed { viewer.light_dir = -viewer.camera.orientation.z; },
                     Some(controls::KEY_SHOW_LIGHT) => if pressed { viewer.show_light = !viewer.show_light; },
+                    Some(controls::KEY_AMBIENT_OCCLUSION) => if pressed {
+                        viewer.mesh_settings.ao = !viewer.mesh_settings.ao;
+                        rebuild_user_mesh(viewer, display);
+                        rebuild_map(viewer, display);
+                    },
+                    Some(controls::KEY_COMPUTED_VISIBILITY) => if pressed {
+                        viewer.mesh_settings.computed_visibility = !viewer.mesh_settings.computed_visibility;
+                        rebuild_user_mesh(viewer, display);
+                    },
+                    Some(controls::KEY_COMPUTED_NORMALS) => if pressed {
+                        viewer.mesh_settings.computed_normals = !viewer.mesh_settings.computed_normals;
+                        rebuild_user_mesh(viewer, display);
+                    },
+                    Some(controls::KEY_EXPORT_OBJ) => if pressed { export_user_obj(viewer); },
+                    Some(controls::KEY_GREEDY_MESHING) => if pressed {
+                        viewer.mesh_settings.meshing = match viewer.mesh_settings.meshing {
+                            kv6::Meshing::Faces => kv6::Meshing::Greedy { match_normals: false },
+                            kv6::Meshing::Greedy { match_normals: false } => kv6::Meshing::Greedy { match_normals: true },
+                            kv6::Meshing::Greedy { match_normals: true } | kv6::Meshing::SurfaceNets => kv6::Meshing::Faces
+                        };
+                        rebuild_user_mesh(viewer, display);
+                        rebuild_map(viewer, display);
+                    },
+                    Some(controls::KEY_SMOOTH_MESHING) => if pressed {
+                        viewer.mesh_settings.meshing = match viewer.mesh_settings.meshing {
+                            kv6::Meshing::SurfaceNets => kv6::Meshing::Faces,
+                            _ => kv6::Meshing::SurfaceNets
+                        };
+                        rebuild_user_mesh(viewer, display);
+                        rebuild_map(viewer, display);
+                    },
                     _ => (),
                 }
             },
@@ -218,9 +572,27 @@ fn render(viewer: &mut Viewer, display: &Display, delta: f32) {
     let light_dir: [f32; 3] = viewer.light_dir.into();
     let aos_team_color: [f32; 3] = viewer.aos_team_color.into();
 
-    target.draw(&viewer.user_kv6.vertex_buffer, &viewer.user_kv6.indices, &viewer.program,
-        &uniform! { perspective: perspective, view: view, model: model, light_dir: light_dir, aos_team_color: aos_team_color },
-        &params).unwrap();
+    if let Some(user_kv6) = &viewer.user_kv6 {
+        target.draw(&user_kv6.vertex_buffer, &user_kv6.indices, &viewer.program,
+            &uniform! { perspective: perspective, view: view, model: model, light_dir: light_dir, aos_team_color: aos_team_color },
+            &params).unwrap();
+    }
+
+    if let Some(map) = &viewer.map {
+        for chunk in &map.chunks {
+            target.draw(&chunk.vertex_buffer, &chunk.indices, &viewer.program,
+                &uniform! { perspective: perspective, view: view, model: model, light_dir: light_dir, aos_team_color: aos_team_color },
+                &params).unwrap();
+        }
+    }
+
+    for (placed_kv6, position) in &viewer.placed_models {
+        let model: [[f32; 4]; 4] = Matrix4::from_translation(*position).into();
+
+        target.draw(&placed_kv6.vertex_buffer, &placed_kv6.indices, &viewer.program,
+            &uniform! { perspective: perspective, view: view, model: model, light_dir: light_dir, aos_team_color: aos_team_color },
+            &params).unwrap();
+    }
 
     if viewer.show_light {
         let model: [[f32; 4]; 4] = Matrix4::from_translation(-viewer.light_dir * 128.0).into();
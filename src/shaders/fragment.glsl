@@ -4,6 +4,7 @@ in vec3 frag_position;
 in vec3 frag_normal;
 in vec3 frag_face;
 in vec3 frag_color;
+in float frag_ao;
 
 uniform vec3 light_dir;
 uniform vec3 aos_team_color;
@@ -26,6 +27,9 @@ void main() {
     float face_diff = max(dot(frag_face, light_norm) * 0.6 + 0.45, 0.0);
     vec3 diffuse = (voxel_diff * 0.75 + face_diff * 0.15) * vec3(1.0);
 
-    vec3 result = (ambient + diffuse) * (vox_color / 255.0);
+    // fully occluded corners keep some light so they don't go black
+    float occlusion = 0.4 + 0.6 * frag_ao;
+
+    vec3 result = (ambient + diffuse) * occlusion * (vox_color / 255.0);
     out_color = vec4(result, 1.0);
 }
\ No newline at end of file
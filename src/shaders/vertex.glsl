@@ -4,6 +4,7 @@ in vec3 position;
 in vec3 normal;
 in vec3 face;
 in vec3 color;
+in float ao;
 
 uniform mat4 perspective;
 uniform mat4 view;
@@ -13,6 +14,7 @@ out vec3 frag_position;
 out vec3 frag_normal;
 out vec3 frag_face;
 out vec3 frag_color;
+out float frag_ao;
 
 void main() {
     gl_Position = perspective * view * model * vec4(position, 1.0);
@@ -20,4 +22,5 @@ void main() {
     frag_normal = normal;
     frag_face = face;
     frag_color = color;
+    frag_ao = ao;
 }
\ No newline at end of file